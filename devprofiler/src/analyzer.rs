@@ -1,82 +1,509 @@
-use git2::{ Repository, Diff, Commit };
-use detect_lang;
+use git2::{ Repository, Delta, Diff, DiffFindOptions, DiffOptions, Commit, Mailmap, Oid, Patch, Signature, Sort, Tree, message_trailers_strs };
+use schemars::JsonSchema;
 use serde::Serialize;
-use sha256::digest;
 use std::path::PathBuf;
 use std::path::Path;
 use std::error::Error;
-use std::collections::HashSet;
-use crate::writer::OutputWriter;
+use std::fs;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, Scope};
+use crate::anonymizer::Anonymizer;
+use crate::checkpoint::RepoCheckpoint;
+use crate::classifier::{FileClass, FileClassifier};
+use crate::exclude::ExcludeRules;
+use crate::config::Config;
+use crate::filter::CommitFilter;
+use crate::writer::{OutputWriter, Record};
 use crate::observer::RuntimeInfo;
 
+/// Commits whose diffs are computed together before being written in order.
+const DIFF_BATCH: usize = 256;
+
+/// Refs whose history is analyzed in addition to HEAD.
+#[derive(Clone)]
+pub struct RefSelection {
+    branches: bool,
+    remotes: bool,
+    tags: bool,
+    patterns: Vec<String>,
+}
+
+impl RefSelection {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            branches: config.get_bool("analysis.branches"),
+            remotes: config.get_bool("analysis.remotes"),
+            tags: config.get_bool("analysis.tags"),
+            patterns: config.get_list("analysis.refs"),
+        }
+    }
+}
+
+/// How the diff of a merge commit is taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeDiff {
+    /// Against the first parent, i.e. everything the merge brought in
+    FirstParent,
+    /// Only files differing from every parent, like `git diff --cc`
+    Combined,
+    /// Merge commits are not emitted at all
+    Skip,
+}
+
+impl MergeDiff {
+    pub const NAMES: [&'static str; 3] = ["first-parent", "combined", "skip"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "first-parent" => Some(Self::FirstParent),
+            "combined" => Some(Self::Combined),
+            "skip" => Some(Self::Skip),
+            _ => None,
+        }
+    }
+}
+
+/// Settings shared by the analyzers of every repository in a run.
+#[derive(Clone)]
+pub struct AnalyzerOptions {
+    pub(crate) incremental: bool,
+    pub(crate) ref_selection: RefSelection,
+    pub(crate) filter: CommitFilter,
+    pub(crate) use_mailmap: bool,
+    /// Contents of the user supplied mailmap, overriding and extending the repository's own
+    pub(crate) extra_mailmap: Option<String>,
+    pub(crate) merge_diff: MergeDiff,
+    /// Similarity in percent above which files count as renamed or copied, 0 disables detection
+    pub(crate) similarity_threshold: u16,
+    pub(crate) excludes: ExcludeRules,
+    pub(crate) anonymizer: Anonymizer,
+    /// Repositories analyzed at the same time
+    pub(crate) jobs: usize,
+    /// Workers diffing the commits of one repository, 0 to share out the
+    /// CPUs left by `jobs`
+    pub(crate) diff_jobs: usize,
+}
+
+impl AnalyzerOptions {
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let extra_mailmap = match config.get_str("analysis.mailmap_file") {
+            mailmap_path if mailmap_path.is_empty() => None,
+            mailmap_path => Some(fs::read_to_string(&mailmap_path)
+                .map_err(|err| format!("Unable to read mailmap {mailmap_path} : {err}"))?),
+        };
+        let merges = config.get_str("analysis.merges");
+        let merge_diff = MergeDiff::from_name(&merges).ok_or_else(|| format!(
+            "Unknown merge handling {merges}, expected one of {}", MergeDiff::NAMES.join(", ")))?;
+        let similarity_threshold = match config.get_int("analysis.similarity_threshold") {
+            threshold @ 0..=100 => threshold as u16,
+            threshold => return Err(format!("Invalid similarity threshold {threshold}, expected 0 to 100").into()),
+        };
+        let jobs = match config.get_int("analysis.jobs") {
+            0 => Self::cpus(),
+            jobs @ 1.. => jobs as usize,
+            jobs => return Err(format!("Invalid number of jobs {jobs}, expected 0 for one per CPU or more").into()),
+        };
+        let diff_jobs = match config.get_int("analysis.diff_jobs") {
+            diff_jobs @ 0.. => diff_jobs as usize,
+            diff_jobs => return Err(format!("Invalid number of diff jobs {diff_jobs}, expected 0 for automatic or more").into()),
+        };
+        Ok(Self {
+            incremental: config.get_bool("analysis.incremental"),
+            ref_selection: RefSelection::from_config(config),
+            filter: CommitFilter::from_config(config)?,
+            use_mailmap: config.get_bool("analysis.use_mailmap"),
+            extra_mailmap,
+            merge_diff,
+            similarity_threshold,
+            excludes: ExcludeRules::from_config(config)?,
+            anonymizer: Anonymizer::from_config(config)?,
+            jobs,
+            diff_jobs,
+        })
+    }
+
+    /// Whether checkpoints are used and updated. Filtered runs only see
+    /// part of the history, so they never are.
+    pub fn uses_checkpoints(&self) -> bool {
+        !self.filter.is_active()
+    }
+
+    /// Options for analyzing `jobs` repositories at once. Unless set, the
+    /// diff workers of each repository share out the CPUs between them.
+    pub fn for_jobs(&self, jobs: usize) -> Self {
+        let mut options = self.clone();
+        options.jobs = jobs;
+        if options.diff_jobs == 0 {
+            options.diff_jobs = (Self::cpus() / jobs.max(1)).max(1);
+        }
+        options
+    }
+
+    fn cpus() -> usize {
+        thread::available_parallelism().map(|cpus| cpus.get()).unwrap_or(1)
+    }
+}
+
+/// Diff workers kept for the whole analysis of a repository, each holding
+/// its own repository handle since a handle cannot be shared between threads.
+struct DiffPool {
+    part_senders: Vec<SyncSender<Vec<Oid>>>,
+    /// None from a worker unable to open the repository
+    info_receivers: Vec<Receiver<Option<Vec<DiffInfo>>>>,
+}
+
+impl DiffPool {
+    fn spawn<'scope, 'env>(scope: &'scope Scope<'scope, 'env>, path: &'env Path, classifier: &'env FileClassifier, options: &'env AnalyzerOptions) -> Self {
+        // a single worker would only wait on the walking thread
+        let count = match options.diff_jobs {
+            diff_jobs if diff_jobs < 2 => 0,
+            diff_jobs => diff_jobs,
+        };
+        let (mut part_senders, mut info_receivers) = (Vec::new(), Vec::new());
+        for _ in 0..count {
+            let (part_sender, part_receiver) = mpsc::sync_channel::<Vec<Oid>>(1);
+            let (info_sender, info_receiver) = mpsc::sync_channel(1);
+            scope.spawn(move || {
+                let repo = Repository::discover(path).ok();
+                for part in part_receiver {
+                    let part_infos = repo.as_ref().map(|repo| part.iter()
+                        .map(|commit_id| RepoAnalyzer::diff_info(repo, classifier, options, *commit_id))
+                        .collect());
+                    if info_sender.send(part_infos).is_err() {
+                        break;
+                    }
+                }
+            });
+            part_senders.push(part_sender);
+            info_receivers.push(info_receiver);
+        }
+        Self { part_senders, info_receivers }
+    }
+}
+
 pub struct RepoAnalyzer {
     repo: Repository,
     path: PathBuf,
     repo_slug: Option<String>,
     provider: Option<String>,
+    options: AnalyzerOptions,
+    /// The repository's .mailmap and mailmap.file
+    repo_mailmap: Option<Mailmap>,
+    extra_mailmap: Option<Mailmap>,
+    classifier: FileClassifier,
 }
 
 impl RepoAnalyzer {
-    pub fn new(path_str: &str, repo_slug: &Option<String>, provider: &Option<String>) -> Result<RepoAnalyzer, Box<dyn Error>> {
+    pub fn new(path_str: &str, repo_slug: &Option<String>, provider: &Option<String>, options: &AnalyzerOptions) -> Result<RepoAnalyzer, Box<dyn Error>> {
         let path = Path::new(path_str);
-        let repo = Repository::discover(&path)?;
+        let repo = Repository::discover(path)?;
+        let repo_mailmap = match options.use_mailmap {
+            // reads .mailmap from the worktree or HEAD, and mailmap.file from git config
+            true => Some(repo.mailmap()?),
+            false => None,
+        };
+        let extra_mailmap = match &options.extra_mailmap {
+            Some(extra_mailmap) => Some(Mailmap::from_buffer(extra_mailmap)?),
+            None => None,
+        };
         Ok(Self {
             path: path.to_owned(),
-            repo: repo,
+            repo,
             repo_slug: repo_slug.to_owned(),
             provider: provider.to_owned(),
+            options: options.clone(),
+            repo_mailmap,
+            extra_mailmap,
+            classifier: FileClassifier::new(),
+        })
+    }
+
+    /// Canonical identity after applying the mailmaps. The user mailmap is
+    /// matched against the raw identity first, so that it overrides the
+    /// repository's entries, then against the identity those resolved to, so
+    /// that it extends them.
+    fn resolve_signature(&self, sig: &Signature) -> Signature<'static> {
+        let resolve = |mailmap: &Option<Mailmap>, sig: &Signature| mailmap.as_ref()
+            .and_then(|mailmap| mailmap.resolve_signature(sig).ok())
+            .unwrap_or_else(|| sig.to_owned());
+        let user_resolved = resolve(&self.extra_mailmap, sig);
+        if user_resolved.name_bytes() != sig.name_bytes() || user_resolved.email_bytes() != sig.email_bytes() {
+            return user_resolved;
+        }
+        resolve(&self.extra_mailmap, &resolve(&self.repo_mailmap, sig))
+    }
+
+    /// Identities from `Co-authored-by: Name <email>` trailers of the commit message.
+    fn parse_co_authors(commit: &Commit) -> Vec<Signature<'static>> {
+        let mut co_authors = Vec::<Signature<'static>>::new();
+        let trailers = match message_trailers_strs(commit.message().unwrap_or_default()) {
+            Ok(trailers) => trailers,
+            Err(_) => return co_authors,
+        };
+        for (key, value) in trailers.iter() {
+            if !key.eq_ignore_ascii_case("co-authored-by") {
+                continue;
+            }
+            let (name, email) = match (value.rfind('<'), value.rfind('>')) {
+                (Some(start), Some(end)) if start < end => (value[..start].trim(), value[start + 1..end].trim()),
+                _ => (value.trim(), value.trim()),
+            };
+            // git2 rejects empty names, fall back to the email as in `git shortlog -e`
+            let name = match name.is_empty() { true => email, false => name };
+            if let Ok(sig) = Signature::new(name, email, &commit.time()) {
+                co_authors.push(sig);
+            }
+        }
+        co_authors
+    }
+
+    /// Key identifying the repository in the checkpoint store.
+    pub fn repo_key(&self) -> String {
+        let git_dir = self.repo.path();
+        git_dir.canonicalize().unwrap_or(git_dir.to_path_buf()).display().to_string()
+    }
+
+    /// Emits every commit reachable from the selected refs, except those
+    /// already reachable from the tips recorded in `previous`. Returns the
+    /// aliases of this and previous runs, and the checkpoint for the next run
+    /// unless some commit could not be read or written.
+    pub fn analyze(&self, writer: &mut dyn OutputWriter, einfo: &mut RuntimeInfo, previous: Option<&RepoCheckpoint>) 
+        -> Result<(HashSet::<String>, Option<RepoCheckpoint>), Box<dyn Error>>{
+        thread::scope(|scope| {
+            let pool = DiffPool::spawn(scope, &self.path, &self.classifier, &self.options);
+            self.walk(writer, einfo, previous, &pool)
         })
     }
 
-    pub fn analyze(&self, writer: &mut OutputWriter, einfo: &mut RuntimeInfo) 
-        -> Result<HashSet::<String>, Box<dyn Error>>{
-        let mut aliases = HashSet::new();
+    fn walk(&self, writer: &mut dyn OutputWriter, einfo: &mut RuntimeInfo, previous: Option<&RepoCheckpoint>, pool: &DiffPool)
+        -> Result<(HashSet::<String>, Option<RepoCheckpoint>), Box<dyn Error>>{
+        let mut aliases: HashSet<String> = previous.iter().flat_map(|prev| prev.aliases.iter().cloned()).collect();
+        let mut all_written = true;
         let mut revwalk = self.repo.revwalk()?;
-        revwalk.push_head()?;
+        // topological order visits every child before its parents, so the refs
+        // reaching a commit are complete by the time it is emitted
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        let mut tip_refs = HashMap::<Oid, Vec<String>>::new();
+        let mut checkpoint = RepoCheckpoint::default();
+        let tips = match self.options.filter.range() {
+            Some((from, to)) => {
+                revwalk.hide(self.repo.revparse_single(from)?.peel_to_commit()?.id())?;
+                vec![(to.to_string(), self.repo.revparse_single(to)?.peel_to_commit()?.id())]
+            },
+            None => self.collect_tips(einfo)?,
+        };
+        for (ref_name, tip) in tips {
+            revwalk.push(tip)?;
+            checkpoint.refs.insert(ref_name.to_string(), tip.to_string());
+            tip_refs.entry(tip).or_default().push(ref_name);
+        }
+        for (ref_name, seen_tip) in previous.iter().flat_map(|prev| prev.refs.iter()) {
+            // rewritten or garbage collected history can no longer be hidden
+            match Oid::from_str(seen_tip).and_then(|oid| self.repo.find_commit(oid)) {
+                Ok(seen_commit) => { revwalk.hide(seen_commit.id())?; },
+                Err(_) => {
+                    let anonymizer = &self.options.anonymizer;
+                    let recorded_ref = anonymizer.apply("refs", ref_name).unwrap_or_else(|| "a ref".to_string());
+                    let recorded_tip = anonymizer.apply("commit_id", seen_tip).unwrap_or_default();
+                    einfo.record_err(format!("Checkpoint of {recorded_ref} at {recorded_tip} no longer exists").as_str());
+                }
+            }
+        }
+        let mut pending_refs = HashMap::<Oid, Rc<BTreeSet<String>>>::new();
+        let repo_name = self.repo_name();
+        let mut batch = Vec::<PendingCommit>::with_capacity(DIFF_BATCH);
         for rev in revwalk {
             match rev {
                 Ok(objid) => {
+                    let mut refs = pending_refs.remove(&objid).unwrap_or_default();
+                    if let Some(names) = tip_refs.get(&objid) {
+                        Rc::make_mut(&mut refs).extend(names.iter().cloned());
+                    }
                     let commit_res = self.repo.find_commit(objid);
                     match commit_res {
                         Ok(commit) => {
-                            aliases.insert(commit.author().email().unwrap_or_default().to_string());
-                            let cinfo = self.extract_commit_obj(&commit);
-                            let serialized = serde_json::to_string(&cinfo).unwrap_or_default();
-                            match writer.writeln(serialized.as_str().as_ref()) {
-                                Ok(_) => {},
-                                Err(writer_err) => {
-                                    einfo.record_err(writer_err.to_string().as_str().as_ref());
+                            for parent_id in commit.parent_ids() {
+                                match pending_refs.get_mut(&parent_id) {
+                                    Some(parent_refs) => {
+                                        if !Rc::ptr_eq(parent_refs, &refs) && !refs.is_subset(parent_refs) {
+                                            Rc::make_mut(parent_refs).extend(refs.iter().cloned());
+                                        }
+                                    },
+                                    None => { pending_refs.insert(parent_id, Rc::clone(&refs)); },
+                                }
+                            }
+                            if self.options.merge_diff == MergeDiff::Skip && commit.parent_count() > 1 {
+                                continue;
+                            }
+                            let raw_author = commit.author().to_owned();
+                            let author = self.resolve_signature(&raw_author);
+                            let filter = &self.options.filter;
+                            let matches_author = |sig: &Signature| filter.matches_author(
+                                sig.name().unwrap_or_default(), sig.email().unwrap_or_default());
+                            let mut co_authors = Vec::<Signature>::new();
+                            let mut co_author_matches = Vec::<bool>::new();
+                            for raw_co_author in Self::parse_co_authors(&commit) {
+                                let co_author = self.resolve_signature(&raw_co_author);
+                                let matched = matches_author(&co_author) || matches_author(&raw_co_author);
+                                match co_authors.iter().position(|seen| seen.email() == co_author.email()) {
+                                    Some(seen_idx) => co_author_matches[seen_idx] |= matched,
+                                    None if co_author.email() != author.email() => {
+                                        co_authors.push(co_author);
+                                        co_author_matches.push(matched);
+                                    },
+                                    None => {},
                                 }
                             }
+                            // authors given up front may use either the raw or the mailmapped identity
+                            let author_matches = matches_author(&author) || matches_author(&raw_author);
+                            if !filter.matches_time(author.when().seconds())
+                                || !(author_matches || co_author_matches.contains(&true)) {
+                                continue;
+                            }
+                            // with an author filter only the matching identities become aliases
+                            if author_matches {
+                                aliases.insert(author.email().unwrap_or_default().to_string());
+                            }
+                            for (co_author, _) in co_authors.iter().zip(&co_author_matches).filter(|(_, matched)| **matched) {
+                                aliases.insert(co_author.email().unwrap_or_default().to_string());
+                            }
+                            let identities = CommitIdentities {
+                                committer: self.resolve_signature(&commit.committer()),
+                                author,
+                                co_authors,
+                            };
+                            batch.push(PendingCommit { commit, identities, refs: refs.iter().cloned().collect() });
+                            if batch.len() == DIFF_BATCH {
+                                all_written &= self.write_batch(&mut batch, pool, &repo_name, writer, einfo);
+                            }
                         },
                         Err(commit_err) => {
+                            // the commit would be hidden from later runs
+                            all_written = false;
                             einfo.record_err(commit_err.to_string().as_str().as_ref());
                         }
                     }
                 },
                 Err(rev_err) => {
+                    all_written = false;
                     einfo.record_err(rev_err.to_string().as_str().as_ref());
                 }
             }
         }
-        Ok(aliases)
+        all_written &= self.write_batch(&mut batch, pool, &repo_name, writer, einfo);
+        checkpoint.aliases = aliases.iter().cloned().collect();
+        Ok((aliases, all_written.then_some(checkpoint)))
+    }
+
+    /// Diffs the pending commits and writes them in walk order. Returns
+    /// false when some commit could not be written.
+    fn write_batch(&self, batch: &mut Vec<PendingCommit>, pool: &DiffPool, repo_name: &str, writer: &mut dyn OutputWriter, einfo: &mut RuntimeInfo) -> bool {
+        let mut all_written = true;
+        let commit_ids: Vec<Oid> = batch.iter().map(|pending| pending.commit.id()).collect();
+        let diff_infos = self.diff_batch(&commit_ids, pool);
+        for (pending, diff_info) in batch.drain(..).zip(diff_infos) {
+            let cinfo = CommitInfo::new(&pending.commit, &pending.identities, diff_info, repo_name, pending.refs, &self.options.anonymizer);
+            match writer.write_record(&Record::Commit(&cinfo)) {
+                Ok(_) => {},
+                Err(writer_err) => {
+                    all_written = false;
+                    einfo.record_err(writer_err.to_string().as_str().as_ref());
+                }
+            }
+        }
+        all_written
+    }
+
+    /// Diff stats of each commit, in the given order. The commits are split
+    /// into contiguous parts, one per worker of the pool.
+    fn diff_batch(&self, commit_ids: &[Oid], pool: &DiffPool) -> Vec<DiffInfo> {
+        let diff_here = |part: &[Oid]| part.iter()
+            .map(|commit_id| Self::diff_info(&self.repo, &self.classifier, &self.options, *commit_id))
+            .collect::<Vec<DiffInfo>>();
+        let workers = pool.part_senders.len().min(commit_ids.len());
+        if workers < 2 {
+            return diff_here(commit_ids);
+        }
+        let parts: Vec<&[Oid]> = commit_ids.chunks(commit_ids.len().div_ceil(workers)).collect();
+        let sent: Vec<bool> = parts.iter().zip(&pool.part_senders)
+            .map(|(part, part_sender)| part_sender.send(part.to_vec()).is_ok())
+            .collect();
+        let mut diff_infos = Vec::<DiffInfo>::with_capacity(commit_ids.len());
+        for ((part, info_receiver), sent) in parts.iter().zip(&pool.info_receivers).zip(sent) {
+            // parts of workers that failed are diffed by this thread
+            match sent.then(|| info_receiver.recv().ok().flatten()).flatten() {
+                Some(part_infos) => diff_infos.extend(part_infos),
+                None => diff_infos.extend(diff_here(part)),
+            }
+        }
+        diff_infos
+    }
+
+    fn diff_info(repo: &Repository, classifier: &FileClassifier, options: &AnalyzerOptions, commit_id: Oid) -> DiffInfo {
+        let commit = repo.find_commit(commit_id).ok();
+        let diff = commit.as_ref().and_then(|commit| Self::extract_diff(repo, options, commit));
+        let tree = commit.as_ref().and_then(|commit| commit.tree().ok());
+        CommitInfo::get_diffs(repo, classifier, options, tree.as_ref(), &diff).unwrap_or_default()
+    }
+
+    /// Resolves HEAD and the selected refs to the commits they point at.
+    fn collect_tips(&self, einfo: &mut RuntimeInfo) -> Result<Vec<(String, Oid)>, Box<dyn Error>> {
+        let mut tips = Vec::<(String, Oid)>::new();
+        if let Ok(head) = self.repo.head().and_then(|head| head.peel_to_commit()) {
+            tips.push(("HEAD".to_string(), head.id()));
+        }
+        let ref_selection = &self.options.ref_selection;
+        let mut globs = Vec::<String>::new();
+        if ref_selection.branches { globs.push("refs/heads/*".to_string()); }
+        if ref_selection.remotes { globs.push("refs/remotes/*".to_string()); }
+        if ref_selection.tags { globs.push("refs/tags/*".to_string()); }
+        for pattern in &ref_selection.patterns {
+            if pattern.starts_with("refs/") {
+                globs.push(pattern.to_string());
+                continue;
+            }
+            match self.repo.revparse_single(pattern).and_then(|obj| obj.peel_to_commit()) {
+                Ok(commit) => tips.push((pattern.to_string(), commit.id())),
+                Err(rev_err) => {
+                    // the error repeats the ref, which is only recorded as the privacy settings allow
+                    eprintln!("Unable to resolve ref {pattern} : {rev_err}");
+                    let recorded_ref = self.options.anonymizer.apply("refs", pattern).unwrap_or_else(|| "a ref".to_string());
+                    einfo.record_err(format!("Unable to resolve ref {recorded_ref}").as_str());
+                }
+            }
+        }
+        for glob in globs {
+            for reference in self.repo.references_glob(&glob)?.flatten() {
+                if reference.kind() == Some(git2::ReferenceType::Symbolic) {
+                    continue;
+                }
+                // tags may point at trees or blobs, which have no history
+                if let (Some(name), Ok(commit)) = (reference.name(), reference.peel_to_commit()) {
+                    tips.push((name.to_string(), commit.id()));
+                }
+            }
+        }
+        if tips.is_empty() {
+            return Err(format!("No commits to analyze in {}", self.path.display()).into());
+        }
+        Ok(tips)
     }
 
     fn extract_reponame(&self) -> &str{
         self.path.as_path()
             .strip_prefix(self.path.as_path().parent().expect("None only if path = /"))
             .expect("Err only if non-parent/prefix argument")
-            .as_os_str().to_str().expect("None only if path is empty").as_ref()
+            .as_os_str().to_str().expect("None only if path is empty")
     }
 
-    fn extract_commit_obj(&self, commit: &Commit) -> CommitInfo {
-        let diff = self.extract_diff(commit);
-        let repo_name = match &self.repo_slug {
+    fn repo_name(&self) -> String {
+        match &self.repo_slug {
             Some(repo_name_val) => {
                 match &self.provider {
                     Some(provider_val) => {
-                        let tmp = provider_val.to_owned() + "/" + repo_name_val;
-                        tmp
+                        provider_val.to_owned() + "/" + repo_name_val
                     }
                     None => {
                         self.extract_reponame().to_string()
@@ -86,126 +513,313 @@ impl RepoAnalyzer {
             None => {
                 self.extract_reponame().to_string()
             }
-        };
-        CommitInfo::new(commit, &diff, &repo_name.as_str())
-    }
-
-    fn extract_diff(&self, commit: &Commit) -> Option<Diff> {
-        let mut diff: Option<Diff> = None;
-        let commit_tree = commit.tree();
-        // TODO - diff should be taken form all parents and intersected
-        let parent = commit.parent(0);
-        if parent.is_ok() && commit_tree.is_ok(){
-            let parent_tree = parent.expect("Checked, is ok").tree();
-            let diff_result = self.repo
-                .diff_tree_to_tree(
-                    Some(&(commit_tree.expect("Checked, is ok"))), 
-                    Some(&(parent_tree.expect("Checked, is ok"))),
-                    None
-                );
-            if diff_result.is_ok() {
-                diff = Some(diff_result.expect("Checked, is ok"));
-            }
         }
-        diff
+    }
+
+    /// Diff of the commit with renames and copies detected, so that moved
+    /// files are not counted as a full delete plus a full add.
+    fn extract_diff<'r>(repo: &'r Repository, options: &AnalyzerOptions, commit: &Commit) -> Option<Diff<'r>> {
+        let mut diff = Self::diff_to_parents(repo, options, commit)?;
+        if options.similarity_threshold > 0 {
+            let mut find_opts = DiffFindOptions::new();
+            find_opts.renames(true)
+                .copies(true)
+                .rename_threshold(options.similarity_threshold)
+                .copy_threshold(options.similarity_threshold);
+            // on failure the diff is left as is, with moves as delete plus add
+            diff.find_similar(Some(&mut find_opts)).ok();
+        }
+        Some(diff)
+    }
+
+    /// Diff from the first parent to the commit, or from the empty tree for
+    /// root commits. With combined merge handling only the files differing
+    /// from every parent are kept.
+    fn diff_to_parents<'r>(repo: &'r Repository, options: &AnalyzerOptions, commit: &Commit) -> Option<Diff<'r>> {
+        let commit_tree = commit.tree().ok()?;
+        let parent_trees: Vec<Tree> = commit.parents()
+            .map(|parent| parent.tree().ok())
+            .collect::<Option<Vec<Tree>>>()?;
+        let first_tree = parent_trees.first();
+        if parent_trees.len() < 2 || options.merge_diff != MergeDiff::Combined {
+            return repo.diff_tree_to_tree(first_tree, Some(&commit_tree), None).ok();
+        }
+        let mut common_paths: Option<BTreeSet<PathBuf>> = None;
+        for parent_tree in &parent_trees {
+            let parent_diff = repo.diff_tree_to_tree(Some(parent_tree), Some(&commit_tree), None).ok()?;
+            let changed: BTreeSet<PathBuf> = parent_diff.deltas()
+                .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()).map(Path::to_path_buf))
+                .collect();
+            common_paths = Some(match common_paths {
+                Some(paths) => paths.intersection(&changed).cloned().collect(),
+                None => changed,
+            });
+        }
+        let common_paths = common_paths.unwrap_or_default();
+        if common_paths.is_empty() {
+            // an empty pathspec matches everything, diff the tree against itself instead
+            return repo.diff_tree_to_tree(Some(&commit_tree), Some(&commit_tree), None).ok();
+        }
+        let mut opts = DiffOptions::new();
+        opts.disable_pathspec_match(true);
+        for path in &common_paths {
+            opts.pathspec(path);
+        }
+        repo.diff_tree_to_tree(first_tree, Some(&commit_tree), Some(&mut opts)).ok()
     }
 }
 
-#[derive(Clone, Debug, Serialize, Default)]
-struct DiffInfo {
-    insertions: usize,
-    deletions: usize,
-    files_changed: usize,
-    file_info: Vec<DiffFileInfo>,
+#[derive(Debug, Serialize, JsonSchema, Default)]
+pub struct UserAlias {
+    pub(crate) alias: Vec::<String>
 }
 
-#[derive(Clone, Debug, Serialize, Default)]
-struct DiffFileInfo {
-    path_hash: String,
-    filename: String,
-    v_language: String,
+#[derive(Clone, Debug, Serialize, JsonSchema, Default)]
+pub struct DiffInfo {
+    pub(crate) insertions: usize,
+    pub(crate) deletions: usize,
+    pub(crate) files_changed: usize,
+    pub(crate) file_info: Vec<DiffFileInfo>,
+    /// Lines and files left out of the stats above by the exclude rules
+    pub(crate) excluded_insertions: usize,
+    pub(crate) excluded_deletions: usize,
+    pub(crate) excluded_files: usize,
 }
 
-#[derive(Clone, Debug, Serialize)]
-struct CommitInfo {
-    commit_id: String,
-    repo_name: String,
-    author_name: String,
-    author_email: String,
-    ts_secs: i64,
-    ts_offset_mins: i64,
-    parents: Vec<String>,
-    diff_info: DiffInfo,
+/// Identifiers and names below are written as configured under
+/// `privacy.fields`, hashed by default, and omitted when dropped.
+#[derive(Clone, Debug, Serialize, JsonSchema, Default)]
+pub struct DiffFileInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) path_hash: Option<String>,
+    /// Stem and extension, each written per its privacy mode, `*.ext` when
+    /// only the extension is kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) filename: Option<String>,
+    pub(crate) v_language: String,
+    /// source, test, docs, config, generated, vendored or other
+    pub(crate) category: String,
+    /// added, modified, deleted, renamed, copied or typechange
+    pub(crate) status: String,
+    /// Hash of the path before a rename or copy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) old_path_hash: Option<String>,
+    pub(crate) insertions: usize,
+    pub(crate) deletions: usize,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct CoAuthorInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) email: Option<String>,
+}
+
+/// Resolved identities of everyone credited on a commit.
+struct CommitIdentities {
+    author: Signature<'static>,
+    committer: Signature<'static>,
+    co_authors: Vec<Signature<'static>>,
+}
+
+/// A commit selected for output, waiting for its diff.
+struct PendingCommit<'repo> {
+    commit: Commit<'repo>,
+    identities: CommitIdentities,
+    refs: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct CommitInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) commit_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) repo_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) author_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) author_email: Option<String>,
+    pub(crate) co_authors: Vec<CoAuthorInfo>,
+    pub(crate) ts_secs: i64,
+    pub(crate) ts_offset_mins: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) committer_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) committer_email: Option<String>,
+    pub(crate) committer_ts_secs: i64,
+    pub(crate) committer_ts_offset_mins: i64,
+    /// Id from a `(cherry picked from commit ...)` line, treated like `commit_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cherry_picked_from: Option<String>,
+    pub(crate) parents: Vec<String>,
+    pub(crate) refs: Vec<String>,
+    pub(crate) diff_info: DiffInfo,
 }
 
 impl CommitInfo {
-    fn new(commit: &Commit, diff: &Option<Diff>, reponame: &str) -> Self {
-        let tsecs = commit.time().seconds();
-        let toffset :i64 = commit.time().offset_minutes().into();
+    fn new(commit: &Commit, identities: &CommitIdentities, diff_info: DiffInfo, reponame: &str, refs: Vec<String>, anonymizer: &Anonymizer) -> Self {
+        let CommitIdentities { author, committer, co_authors } = identities;
+        // commit.time() is the committer time, the author time only changes with the patch itself
+        let tsecs = author.when().seconds();
+        let toffset :i64 = author.when().offset_minutes().into();
         let mut cparents :Vec<String>  = Vec::new();
         for c in commit.parents() {
-            cparents.push(digest(c.id().to_string()));
+            cparents.extend(anonymizer.apply("commit_id", &c.id().to_string()));
         }
         Self {
-            commit_id: digest(commit.id().to_string()),
-            repo_name: reponame.to_string(),
-            author_name: digest(commit.author().name().unwrap_or_default().to_string()),
-            author_email: digest(commit.author().email().unwrap_or_default().to_string()),
+            commit_id: anonymizer.apply("commit_id", &commit.id().to_string()),
+            repo_name: anonymizer.apply("repo_name", reponame),
+            author_name: anonymizer.apply("author_name", author.name().unwrap_or_default()),
+            author_email: anonymizer.apply("author_email", author.email().unwrap_or_default()),
+            co_authors: co_authors.iter().map(|co_author| CoAuthorInfo {
+                name: anonymizer.apply("co_authors", co_author.name().unwrap_or_default()),
+                email: anonymizer.apply("co_authors", co_author.email().unwrap_or_default()),
+            }).filter(|co_author| co_author.email.is_some()).collect(),
             ts_secs: tsecs,
             ts_offset_mins: toffset,
+            committer_name: anonymizer.apply("committer_name", committer.name().unwrap_or_default()),
+            committer_email: anonymizer.apply("committer_email", committer.email().unwrap_or_default()),
+            committer_ts_secs: commit.time().seconds(),
+            committer_ts_offset_mins: commit.time().offset_minutes().into(),
+            cherry_picked_from: Self::cherry_picked_from(commit)
+                .and_then(|source_id| anonymizer.apply("commit_id", &source_id)),
             parents: cparents,
-            diff_info: Self::get_diffs(diff).unwrap_or_default(),
+            refs: refs.iter().filter_map(|ref_name| anonymizer.apply("refs", ref_name)).collect(),
+            diff_info,
         }
     }
     
-    fn get_diffs(diff: &Option<Diff>) -> Option<DiffInfo>{
-        if diff.is_none() {
-            return None;
-        }
-        let diff_obj = diff.as_ref().expect("Checked, is not none");
-        let mut diffvec: Vec<DiffFileInfo> = Vec::new();
-        for delta in diff_obj.deltas() {
+    /// Source commit recorded by `git cherry-pick -x`, the last one if picked repeatedly.
+    fn cherry_picked_from(commit: &Commit) -> Option<String> {
+        commit.message().unwrap_or_default().lines().rev()
+            .filter_map(|line| line.trim().strip_prefix("(cherry picked from commit "))
+            .filter_map(|rest| rest.strip_suffix(')'))
+            .map(|source_id| source_id.trim().to_string())
+            .find(|source_id| Oid::from_str(source_id).is_ok())
+    }
+
+    fn get_diffs(repo: &Repository, classifier: &FileClassifier, options: &AnalyzerOptions, tree: Option<&Tree>, diff: &Option<Diff>) -> Option<DiffInfo>{
+        let diff_obj = diff.as_ref()?;
+        let mut diff_info = DiffInfo::default();
+        for (idx, delta) in diff_obj.deltas().enumerate() {
             let fpath = delta.new_file().path();
-            if fpath.is_some() {
-                let filepath = fpath.expect("fpath is some, conditional check on block");
-                let lang = match detect_lang::from_path(filepath) {
-                    Some(langid) => langid.id(),
-                    None => "None",
+            if let Some(filepath) = fpath {
+                // deleted files are classified by their last content
+                let blob_id = match delta.status() {
+                    Delta::Deleted => delta.old_file().id(),
+                    _ => delta.new_file().id(),
                 };
-                diffvec.push(DiffFileInfo::new(&filepath, lang));
-            }
-        }
-        let stats = diff_obj.stats();
-        match stats.is_ok() {
-            true => {
-                let stats_obj = stats.expect("stats is ok, conditional check on block");
-                Some(DiffInfo {
-                    insertions: stats_obj.insertions(),
-                    deletions: stats_obj.deletions(),
-                    files_changed: stats_obj.files_changed(),
-                    file_info: diffvec,
-                })
+                let class = classifier.classify(repo, tree, filepath, blob_id);
+                // binary files have no lines to count
+                let (insertions, deletions, is_binary) = match Patch::from_diff(diff_obj, idx) {
+                    Ok(Some(patch)) => {
+                        let (_, insertions, deletions) = patch.line_stats().unwrap_or_default();
+                        (insertions, deletions, patch.delta().flags().is_binary())
+                    },
+                    _ => (0, 0, true),
+                };
+                if options.excludes.is_excluded(filepath, &class, is_binary) {
+                    diff_info.excluded_insertions += insertions;
+                    diff_info.excluded_deletions += deletions;
+                    diff_info.excluded_files += 1;
+                    continue;
+                }
+                let old_path = match delta.status() {
+                    Delta::Renamed | Delta::Copied => delta.old_file().path(),
+                    _ => None,
+                };
+                diff_info.insertions += insertions;
+                diff_info.deletions += deletions;
+                diff_info.files_changed += 1;
+                diff_info.file_info.push(DiffFileInfo::new(filepath, old_path, &class, delta.status(), (insertions, deletions), &options.anonymizer));
             }
-            false => None
         }
+        Some(diff_info)
     }
 }
 
 impl DiffFileInfo {
-    fn new(path: &Path, lang: &str) -> Self {
-        let stemname = digest(
+    fn new(path: &Path, old_path: Option<&Path>, class: &FileClass, status: Delta, line_stats: (usize, usize), anonymizer: &Anonymizer) -> Self {
+        let (insertions, deletions) = line_stats;
+        let stemname = anonymizer.apply("filename",
             path.file_stem()
             .expect("Not none as filename must exist in git history")
-            .to_str().unwrap_or_default().to_string());
-        let extension = path.extension().unwrap_or_default().to_str();
-        let hashed_fname = match extension.is_none() {
-            true => stemname,
-            false => stemname + "." + extension.expect("extension is some, conditional check on block"),
+            .to_str().unwrap_or_default());
+        let extension = path.extension().and_then(|ext| ext.to_str())
+            .and_then(|ext| anonymizer.apply("extension", ext));
+        let hashed_fname = match (stemname, extension) {
+            (Some(stem), Some(ext)) => Some(stem + "." + &ext),
+            // only the extension is kept
+            (None, Some(ext)) => Some(format!("*.{ext}")),
+            (stem, None) => stem,
         };
+        let path_str = |path: &Path| path.to_path_buf().into_os_string().into_string().unwrap_or_default();
         Self {
-            path_hash: digest(path.to_path_buf().into_os_string().into_string().unwrap_or_default()),
+            path_hash: anonymizer.apply("path", &path_str(path)),
             filename: hashed_fname,
-            v_language: lang.to_string().to_owned(),
+            v_language: class.language.to_owned(),
+            category: class.category.to_string(),
+            status: Self::status_name(status).to_string(),
+            old_path_hash: old_path.and_then(|old| anonymizer.apply("path", &path_str(old))),
+            insertions,
+            deletions,
         }
     }
-}
\ No newline at end of file
+
+    fn status_name(status: Delta) -> &'static str {
+        match status {
+            Delta::Added => "added",
+            Delta::Deleted => "deleted",
+            Delta::Renamed => "renamed",
+            Delta::Copied => "copied",
+            Delta::Typechange => "typechange",
+            _ => "modified",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn test_repo(name: &str) -> (PathBuf, Repository) {
+        let path = env::temp_dir().join(format!("devprofiler-{name}-{}", process::id()));
+        let _res = fs::remove_dir_all(&path);
+        let repo = Repository::init(&path).unwrap();
+        (path, repo)
+    }
+
+    fn commit_file(repo: &Repository, content: &str, parents: &[&Commit]) -> Oid {
+        let blob_id = repo.blob(content.as_bytes()).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("main.rs", blob_id, 0o100644).unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let sig = Signature::now("Jane", "jane@example.com").unwrap();
+        repo.commit(None, &sig, &sig, "change", &tree, parents).unwrap()
+    }
+
+    fn options() -> AnalyzerOptions {
+        // nothing is hashed at the full level, so no key file is created
+        let mut config = Config::defaults();
+        config.set_cli("privacy.level", Some("full"));
+        AnalyzerOptions::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn diffs_from_the_parent_to_the_commit() {
+        let (path, repo) = test_repo("diff-direction");
+        let root_id = commit_file(&repo, "one\ntwo\nthree\n", &[]);
+        let root = repo.find_commit(root_id).unwrap();
+        let child_id = commit_file(&repo, "one\nfour\n", &[&root]);
+        let (classifier, options) = (FileClassifier::new(), options());
+        let root_info = RepoAnalyzer::diff_info(&repo, &classifier, &options, root_id);
+        assert_eq!((root_info.insertions, root_info.deletions, root_info.files_changed), (3, 0, 1));
+        let child_info = RepoAnalyzer::diff_info(&repo, &classifier, &options, child_id);
+        assert_eq!((child_info.insertions, child_info.deletions, child_info.files_changed), (1, 2, 1));
+        assert_eq!(child_info.file_info[0].status, "modified");
+        let _res = fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,170 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::config::Config;
+
+/// Fields whose treatment is configurable under `privacy.fields.<name>`,
+/// with their modes at the minimal, standard and full privacy levels.
+const FIELD_LEVELS: [(&str, [FieldMode; 3]); 13] = {
+    use FieldMode::{Clear, Dropped, Hashed};
+    [
+        ("commit_id", [Hashed, Hashed, Clear]),
+        ("repo_name", [Hashed, Clear, Clear]),
+        ("refs", [Dropped, Clear, Clear]),
+        ("author_name", [Dropped, Hashed, Clear]),
+        ("author_email", [Hashed, Hashed, Clear]),
+        ("committer_name", [Dropped, Hashed, Clear]),
+        ("committer_email", [Dropped, Hashed, Clear]),
+        ("co_authors", [Dropped, Hashed, Clear]),
+        ("path", [Hashed, Hashed, Clear]),
+        ("filename", [Dropped, Hashed, Clear]),
+        ("extension", [Dropped, Clear, Clear]),
+        ("aliases", [Hashed, Hashed, Clear]),
+        ("blame_author", [Hashed, Clear, Clear]),
+    ]
+};
+
+/// Privacy levels, from the least to the most data written out.
+pub const LEVELS: [&str; 3] = ["minimal", "standard", "full"];
+
+const KEY_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldMode {
+    /// HMAC-SHA256 with the local key, hex encoded
+    Hashed,
+    Clear,
+    /// Not written at all
+    Dropped,
+}
+
+impl FieldMode {
+    pub const NAMES: [&'static str; 3] = ["hashed", "clear", "dropped"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Hashed => "hashed",
+            Self::Clear => "clear",
+            Self::Dropped => "dropped",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hashed" => Some(Self::Hashed),
+            "clear" => Some(Self::Clear),
+            "dropped" => Some(Self::Dropped),
+            _ => None,
+        }
+    }
+}
+
+/// Applies the configured mode of each field to the values written out.
+#[derive(Clone)]
+pub struct Anonymizer {
+    key: Vec<u8>,
+    modes: BTreeMap<&'static str, FieldMode>,
+}
+
+impl Anonymizer {
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let modes = Self::field_modes(config)?;
+        // the key is only created once something is hashed with it
+        let key = match modes.values().any(|mode| *mode == FieldMode::Hashed) {
+            true => Self::load_key(&Self::key_path(config), config.get_bool("output.dry_run"))?,
+            false => Vec::new(),
+        };
+        Ok(Self { key, modes })
+    }
+
+    /// Mode of every field, taken from `privacy.level` unless overridden
+    /// under `privacy.fields`.
+    pub fn field_modes(config: &Config) -> Result<BTreeMap<&'static str, FieldMode>, String> {
+        let level = config.get_str("privacy.level");
+        let level_idx = LEVELS.iter().position(|name| *name == level).ok_or_else(|| format!(
+            "Unknown privacy level {level}, expected one of {}", LEVELS.join(", ")))?;
+        let mut modes = BTreeMap::new();
+        for (field, level_modes) in FIELD_LEVELS {
+            let mode = match config.get_str(&format!("privacy.fields.{field}")) {
+                mode_name if mode_name.is_empty() => level_modes[level_idx],
+                mode_name => FieldMode::from_name(&mode_name).ok_or_else(|| format!(
+                    "Unknown mode {mode_name} for privacy.fields.{field}, expected one of {}",
+                    FieldMode::NAMES.join(", ")))?,
+            };
+            modes.insert(field, mode);
+        }
+        Ok(modes)
+    }
+
+    pub fn key_path(config: &Config) -> PathBuf {
+        match config.get_str("privacy.key_file") {
+            configured if !configured.is_empty() => PathBuf::from(configured),
+            _ => match Config::user_config_dir() {
+                Some(dir) => dir.join("devprofiler").join("hash.key"),
+                None => PathBuf::from(".devprofiler-hash.key"),
+            },
+        }
+    }
+
+    /// Reads the hex encoded key, generating a random one on first use. A
+    /// dry run writes nothing, so it hashes with a key kept in memory.
+    fn load_key(path: &Path, dry_run: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+        if path.is_file() {
+            let hex_key = fs::read_to_string(path)?;
+            return Self::decode_hex(hex_key.trim())
+                .ok_or_else(|| format!("Invalid hash key in {}, expected hex", path.display()).into());
+        }
+        let mut key = vec![0u8; KEY_LEN];
+        getrandom::getrandom(&mut key).map_err(|err| format!("Unable to generate a hash key : {err}"))?;
+        if dry_run {
+            return Ok(key);
+        }
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut key_file = options.open(path)
+            .map_err(|err| format!("Unable to create hash key {} : {err}", path.display()))?;
+        writeln!(key_file, "{}", Self::encode_hex(&key))?;
+        Ok(key)
+    }
+
+    fn encode_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+        if hex.is_empty() || !hex.len().is_multiple_of(2) {
+            return None;
+        }
+        (0..hex.len()).step_by(2)
+            .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+            .collect()
+    }
+
+    pub fn mode(&self, field: &str) -> FieldMode {
+        self.modes.get(field).copied().unwrap_or(FieldMode::Hashed)
+    }
+
+    pub fn hash(&self, value: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(value.as_bytes());
+        Self::encode_hex(&mac.finalize().into_bytes())
+    }
+
+    /// The value of `field` as written out, None when the field is dropped.
+    pub fn apply(&self, field: &str, value: &str) -> Option<String> {
+        match self.mode(field) {
+            FieldMode::Hashed => Some(self.hash(value)),
+            FieldMode::Clear => Some(value.to_string()),
+            FieldMode::Dropped => None,
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::config::Config;
+
+const STATE_VERSION: u32 = 1;
+
+/// Tips of every ref analyzed in a repository, keyed by ref name.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct RepoCheckpoint {
+    pub refs: BTreeMap<String, String>,
+    /// Aliases of every commit analyzed so far, offered again by runs that
+    /// find no new commits
+    pub aliases: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    repos: BTreeMap<String, RepoCheckpoint>,
+}
+
+/// Per-repository checkpoints persisted between runs, so that later runs
+/// only analyze commits that are new since the last one.
+pub struct CheckpointStore {
+    path: PathBuf,
+    repos: BTreeMap<String, RepoCheckpoint>,
+}
+
+impl CheckpointStore {
+    pub fn load(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let path = match config.get_str("state.path") {
+            configured if !configured.is_empty() => PathBuf::from(configured),
+            _ => Self::default_path(),
+        };
+        if !path.is_file() {
+            return Ok(Self { path, repos: BTreeMap::new() });
+        }
+        let state: StateFile = serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|err| format!("Unable to parse state file {} : {err}", path.display()))?;
+        if state.version != STATE_VERSION {
+            return Err(format!("Unsupported state file version {} in {}", state.version, path.display()).into());
+        }
+        Ok(Self { path, repos: state.repos })
+    }
+
+    fn default_path() -> PathBuf {
+        let state_dir = match env::var_os("XDG_STATE_HOME") {
+            Some(xdg_dir) => Some(PathBuf::from(xdg_dir)),
+            None => env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state")),
+        };
+        match state_dir {
+            Some(dir) => dir.join("devprofiler").join("checkpoints.json"),
+            None => PathBuf::from(".devprofiler-checkpoints.json"),
+        }
+    }
+
+    pub fn get(&self, repo_key: &str) -> Option<&RepoCheckpoint> {
+        self.repos.get(repo_key)
+    }
+
+    pub fn update(&mut self, repo_key: &str, checkpoint: RepoCheckpoint) {
+        self.repos.insert(repo_key.to_string(), checkpoint);
+    }
+
+    /// Writes the state file atomically, through a temporary file renamed
+    /// over the previous one.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let state = StateFile { version: STATE_VERSION, repos: self.repos.clone() };
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&state)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
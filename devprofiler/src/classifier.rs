@@ -0,0 +1,452 @@
+use git2::{Oid, Repository, Tree};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use crate::exclude::anchor_pattern;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Languages detect_lang does not know by extension, in the same id style.
+const EXTRA_EXTENSIONS: &[(&str, &str)] = &[
+    ("adoc", "asciidoc"), ("bash", "shell"), ("bzl", "starlark"), ("cfg", "ini"),
+    ("cjs", "javascript"), ("clj", "clojure"), ("cmake", "cmake"), ("conf", "ini"),
+    ("cts", "typescript"), ("ex", "elixir"), ("exs", "elixir"), ("erl", "erlang"),
+    ("fish", "fish"), ("fs", "fsharp"), ("gemspec", "ruby"), ("gql", "graphql"),
+    ("gradle", "groovy"), ("graphql", "graphql"), ("groovy", "groovy"), ("hcl", "hcl"),
+    ("hh", "cpp"), ("hs", "haskell"), ("jl", "julia"), ("jsx", "javascript"),
+    ("kts", "kotlin"), ("m", "objective-c"), ("mjs", "javascript"), ("mk", "makefile"),
+    ("ml", "ocaml"), ("mm", "objective-cpp"), ("mts", "typescript"), ("nix", "nix"),
+    ("php", "php"), ("properties", "properties"), ("proto", "protobuf"), ("ps1", "powershell"),
+    ("r", "r"), ("rst", "restructuredtext"), ("sbt", "scala"), ("svelte", "svelte"),
+    ("svg", "xml"), ("tf", "terraform"), ("tsx", "typescript"), ("txt", "text"),
+    ("vue", "vue"), ("zig", "zig"), ("zsh", "shell"),
+];
+
+/// Files recognized by their whole name, compared case-insensitively.
+const FILENAMES: &[(&str, &str)] = &[
+    (".bash_profile", "shell"), (".bashrc", "shell"), (".dockerignore", "ignore"),
+    (".editorconfig", "editorconfig"), (".env", "dotenv"), (".gitattributes", "gitattributes"),
+    (".gitignore", "ignore"), (".gitmodules", "gitconfig"), (".profile", "shell"),
+    (".zshrc", "shell"), ("build", "starlark"), ("build.bazel", "starlark"),
+    ("cargo.lock", "toml"), ("cmakelists.txt", "cmake"), ("containerfile", "dockerfile"),
+    ("dockerfile", "dockerfile"), ("gemfile", "ruby"), ("gemfile.lock", "text"),
+    ("gnumakefile", "makefile"), ("go.mod", "go-module"), ("go.sum", "go-checksums"),
+    ("guardfile", "ruby"), ("jenkinsfile", "groovy"), ("makefile", "makefile"),
+    ("pipfile", "toml"), ("podfile", "ruby"), ("procfile", "procfile"), ("rakefile", "ruby"),
+    ("vagrantfile", "ruby"), ("workspace", "starlark"), ("yarn.lock", "yarn-lock"),
+];
+
+/// Names used by shebangs, modelines and linguist-language for known ids.
+const ALIASES: &[(&str, &str)] = &[
+    ("ash", "shell"), ("bash", "shell"), ("c#", "csharp"), ("c++", "cpp"), ("dash", "shell"),
+    ("deno", "typescript"), ("docker", "dockerfile"), ("js", "javascript"), ("ksh", "shell"),
+    ("make", "makefile"), ("node", "javascript"), ("nodejs", "javascript"),
+    ("objective-c++", "objective-cpp"), ("objc", "objective-c"), ("perl6", "raku"),
+    ("pwsh", "powershell"), ("py", "python"), ("rscript", "r"), ("sh", "shell"),
+    ("tclsh", "tcl"), ("ts", "typescript"), ("yml", "yaml"), ("zsh", "shell"),
+];
+
+const VENDORED_DIRS: &[&str] = &[
+    "bower_components", "godeps", "node_modules", "third-party", "third_party",
+    "thirdparty", "vendor", "vendors",
+];
+const GENERATED_FILENAMES: &[&str] = &[
+    "cargo.lock", "composer.lock", "flake.lock", "gemfile.lock", "go.sum", "mix.lock",
+    "package-lock.json", "packages.lock.json", "pipfile.lock", "pnpm-lock.yaml",
+    "podfile.lock", "poetry.lock", "yarn.lock",
+];
+const GENERATED_SUFFIXES: &[&str] = &[
+    ".designer.cs", ".freezed.dart", ".g.dart", ".js.map", ".css.map", ".min.css",
+    ".min.js", ".pb.cc", ".pb.go", ".pb.h", "_pb2.py", "_pb2_grpc.py",
+];
+const TEST_DIRS: &[&str] = &["__tests__", "e2e", "spec", "specs", "test", "testdata", "tests"];
+const DOCS_DIRS: &[&str] = &["doc", "docs", "documentation"];
+const DOCS_STEMS: &[&str] = &["authors", "changelog", "contributing", "copying", "license", "readme"];
+const DOCS_LANGUAGES: &[&str] = &["asciidoc", "markdown", "restructuredtext", "tex", "text"];
+const CONFIG_LANGUAGES: &[&str] = &[
+    "dotenv", "editorconfig", "gitattributes", "gitconfig", "hcl", "ignore", "ini", "json",
+    "jsonc", "properties", "toml", "xml", "yaml",
+];
+
+/// Lines at the start and end of a file searched for modelines and
+/// generated-code markers.
+const SNIFF_LINES: usize = 5;
+
+/// Language and role of a file, `language` being "None" when unknown.
+pub struct FileClass {
+    pub language: String,
+    /// source, test, docs, config, generated, vendored or other
+    pub category: &'static str,
+}
+
+/// State of an attribute given in a `.gitattributes` line.
+#[derive(Clone)]
+enum AttrState {
+    Set,
+    Unset,
+    Value(String),
+}
+
+/// One line of a `.gitattributes` file.
+struct AttrRule {
+    /// Matched against the path relative to the directory of the file
+    glob: GlobMatcher,
+    /// None when the attribute is reset to unspecified with `!`
+    attrs: Vec<(String, Option<AttrState>)>,
+}
+
+/// Classifies files by `.gitattributes` linguist overrides, modelines,
+/// filename, extension and shebang, in that order.
+pub struct FileClassifier {
+    vim_modeline: Regex,
+    emacs_modeline: Regex,
+    /// Parsed `.gitattributes` files, by blob id
+    attr_files: Mutex<HashMap<Oid, Arc<Vec<AttrRule>>>>,
+}
+
+impl FileClassifier {
+    pub fn new() -> Self {
+        Self {
+            vim_modeline: Regex::new(r"(?:vi|vim|ex):.*\b(?:ft|filetype|syntax)=([\w+#-]+)")
+                .expect("Valid regex"),
+            emacs_modeline: Regex::new(r"-\*-\s*(?:.*;\s*)?(?:mode:\s*)?([\w+#-]+)\s*(?:;.*)?-\*-")
+                .expect("Valid regex"),
+            attr_files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Classifies `path` at the content of `blob_id`, which may be zero
+    /// when no content is available. Attributes are read from the
+    /// `.gitattributes` files of `tree`, the tree of the commit being
+    /// classified.
+    pub fn classify(&self, repo: &Repository, tree: Option<&Tree>, path: &Path, blob_id: Oid) -> FileClass {
+        let attrs = self.attributes(repo, tree, path);
+        let blob = match blob_id.is_zero() {
+            true => None,
+            false => repo.find_blob(blob_id).ok().filter(|blob| !blob.is_binary()),
+        };
+        let content = blob.as_ref().map(|blob| String::from_utf8_lossy(blob.content()));
+        let sniffed: Vec<&str> = match &content {
+            Some(text) => {
+                let lines: Vec<&str> = text.lines().collect();
+                let tail_start = lines.len().saturating_sub(SNIFF_LINES).max(SNIFF_LINES.min(lines.len()));
+                lines[..SNIFF_LINES.min(lines.len())].iter()
+                    .chain(lines[tail_start..].iter()).copied().collect()
+            },
+            None => Vec::new(),
+        };
+        let language = Self::attr_str(&attrs, "linguist-language").map(|name| Self::normalize(&name))
+            .or_else(|| self.modeline_language(&sniffed))
+            .or_else(|| Self::path_language(path))
+            .map(|lang| match lang.as_str() {
+                // .h is shared between C, C++ and Objective-C
+                "c" if Self::has_extension(path, "h") => Self::header_language(content.as_deref()).to_string(),
+                _ => lang,
+            })
+            .or_else(|| content.as_deref().and_then(Self::shebang_language))
+            .unwrap_or_else(|| "None".to_string());
+        let category = Self::category(&attrs, path, &language, &sniffed);
+        FileClass { language, category }
+    }
+
+    /// Attributes given to `path` by the `.gitattributes` files of `tree`, in
+    /// increasing precedence: files of deeper directories and later lines win.
+    fn attributes(&self, repo: &Repository, tree: Option<&Tree>, path: &Path) -> Vec<(String, Option<AttrState>)> {
+        let mut attrs = Vec::new();
+        let tree = match tree {
+            Some(tree) => tree,
+            None => return attrs,
+        };
+        let dirs: Vec<&Path> = path.ancestors().skip(1).collect();
+        for dir in dirs.into_iter().rev() {
+            let entry = match tree.get_path(&dir.join(".gitattributes")) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let relative = path.strip_prefix(dir).unwrap_or(path);
+            for rule in self.attr_rules(repo, entry.id()).iter() {
+                if rule.glob.is_match(relative) {
+                    attrs.extend(rule.attrs.iter().cloned());
+                }
+            }
+        }
+        attrs
+    }
+
+    fn attr_rules(&self, repo: &Repository, blob_id: Oid) -> Arc<Vec<AttrRule>> {
+        let mut attr_files = self.attr_files.lock().expect("Attribute cache lock is not poisoned");
+        attr_files.entry(blob_id).or_insert_with(|| {
+            let rules = match repo.find_blob(blob_id) {
+                Ok(blob) => Self::parse_attr_file(&String::from_utf8_lossy(blob.content())),
+                Err(_) => Vec::new(),
+            };
+            Arc::new(rules)
+        }).clone()
+    }
+
+    fn parse_attr_file(content: &str) -> Vec<AttrRule> {
+        content.lines().filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pattern = fields.next().filter(|pattern| !pattern.starts_with('#'))?;
+            // macros only define other attributes, and patterns ending in a
+            // slash never match files
+            if pattern.starts_with("[attr]") || pattern.ends_with('/') {
+                return None;
+            }
+            let glob = GlobBuilder::new(&anchor_pattern(pattern)).literal_separator(true).build().ok()?;
+            let attrs = fields.map(|field| {
+                if let Some(name) = field.strip_prefix('-') {
+                    (name.to_string(), Some(AttrState::Unset))
+                } else if let Some(name) = field.strip_prefix('!') {
+                    (name.to_string(), None)
+                } else {
+                    match field.split_once('=') {
+                        Some((name, value)) => (name.to_string(), Some(AttrState::Value(value.to_string()))),
+                        None => (field.to_string(), Some(AttrState::Set)),
+                    }
+                }
+            }).collect();
+            Some(AttrRule { glob: glob.compile_matcher(), attrs })
+        }).collect()
+    }
+
+    fn attr_state<'a>(attrs: &'a [(String, Option<AttrState>)], name: &str) -> Option<&'a AttrState> {
+        attrs.iter().rev().find(|(attr, _)| attr == name).and_then(|(_, state)| state.as_ref())
+    }
+
+    fn attr_str(attrs: &[(String, Option<AttrState>)], name: &str) -> Option<String> {
+        match Self::attr_state(attrs, name)? {
+            AttrState::Value(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Some(true) when the attribute is set, Some(false) when explicitly unset.
+    fn attr_flag(attrs: &[(String, Option<AttrState>)], name: &str) -> Option<bool> {
+        match Self::attr_state(attrs, name)? {
+            AttrState::Set => Some(true),
+            AttrState::Unset => Some(false),
+            AttrState::Value(value) => Some(value != "false"),
+        }
+    }
+
+    /// Maps a language name from a modeline, shebang or attribute to an id.
+    fn normalize(name: &str) -> String {
+        let lowered = name.trim().to_lowercase();
+        if let Some((_, id)) = ALIASES.iter().find(|(alias, _)| *alias == lowered) {
+            return id.to_string();
+        }
+        match Self::extension_language(&lowered) {
+            Some(id) => id,
+            None => lowered.replace(' ', "-"),
+        }
+    }
+
+    fn extension_language(extension: &str) -> Option<String> {
+        match detect_lang::from_extension(extension) {
+            Some(langid) => Some(langid.id().to_string()),
+            None => EXTRA_EXTENSIONS.iter().find(|(ext, _)| *ext == extension).map(|(_, id)| id.to_string()),
+        }
+    }
+
+    fn has_extension(path: &Path, extension: &str) -> bool {
+        path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+    }
+
+    fn path_language(path: &Path) -> Option<String> {
+        let filename = path.file_name()?.to_str()?.to_lowercase();
+        if let Some((_, id)) = FILENAMES.iter().find(|(name, _)| *name == filename) {
+            return Some(id.to_string());
+        }
+        if filename.starts_with("dockerfile.") || filename.ends_with(".dockerfile") {
+            return Some("dockerfile".to_string());
+        }
+        Self::extension_language(&path.extension()?.to_str()?.to_lowercase())
+    }
+
+    fn modeline_language(&self, sniffed: &[&str]) -> Option<String> {
+        sniffed.iter().find_map(|line| {
+            self.vim_modeline.captures(line)
+                .or_else(|| self.emacs_modeline.captures(line))
+                .map(|caps| Self::normalize(&caps[1]))
+        })
+    }
+
+    fn shebang_language(content: &str) -> Option<String> {
+        let mut words = content.lines().next()?.strip_prefix("#!")?.split_whitespace();
+        let mut interpreter = Path::new(words.next()?).file_name()?.to_str()?;
+        if interpreter == "env" {
+            interpreter = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+        }
+        // python3.11 and perl5 name the same language as python and perl
+        let name = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+        Some(Self::normalize(name))
+    }
+
+    fn header_language(content: Option<&str>) -> &'static str {
+        let text = content.unwrap_or_default();
+        if ["@interface", "@end", "#import", "@protocol"].iter().any(|marker| text.contains(marker)) {
+            return "objective-c";
+        }
+        let cpp_markers = ["class ", "namespace ", "template<", "template <", "std::", "public:", "private:", "nullptr"];
+        match cpp_markers.iter().any(|marker| text.contains(marker)) {
+            true => "cpp",
+            false => "c",
+        }
+    }
+
+    fn category(attrs: &[(String, Option<AttrState>)], path: &Path, language: &str, sniffed: &[&str]) -> &'static str {
+        let vendored = Self::attr_flag(attrs, "linguist-vendored");
+        let generated = Self::attr_flag(attrs, "linguist-generated");
+        let documentation = Self::attr_flag(attrs, "linguist-documentation");
+        let dirs: Vec<String> = path.parent().into_iter()
+            .flat_map(|parent| parent.iter())
+            .map(|dir| dir.to_string_lossy().to_lowercase())
+            .collect();
+        let filename = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let lower_name = filename.to_lowercase();
+        let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+        if vendored.unwrap_or_else(|| dirs.iter().any(|dir| VENDORED_DIRS.contains(&dir.as_str()))) {
+            return "vendored";
+        }
+        if generated.unwrap_or_else(|| GENERATED_FILENAMES.contains(&lower_name.as_str())
+            || GENERATED_SUFFIXES.iter().any(|suffix| lower_name.ends_with(suffix))
+            || lower_name.contains(".generated.")
+            || sniffed.iter().any(|line| Self::is_generated_marker(line))) {
+            return "generated";
+        }
+        if documentation.unwrap_or(false) {
+            return "docs";
+        }
+        let lower_stem = stem.to_lowercase();
+        if dirs.iter().any(|dir| TEST_DIRS.contains(&dir.as_str()))
+            || lower_stem.starts_with("test_")
+            || ["_test", "_tests", "_spec", ".test", ".spec"].iter().any(|suffix| lower_stem.ends_with(suffix))
+            || (stem.len() > 4 && (stem.ends_with("Test") || stem.ends_with("Tests"))) {
+            return "test";
+        }
+        if documentation.is_none() && (DOCS_LANGUAGES.contains(&language)
+            || dirs.iter().any(|dir| DOCS_DIRS.contains(&dir.as_str()))
+            || DOCS_STEMS.contains(&lower_stem.as_str())) {
+            return "docs";
+        }
+        if CONFIG_LANGUAGES.contains(&language) || (filename.starts_with('.') && language == "None") {
+            return "config";
+        }
+        match language {
+            "None" => "other",
+            _ => "source",
+        }
+    }
+
+    fn is_generated_marker(line: &str) -> bool {
+        let lowered = line.to_lowercase();
+        lowered.contains("@generated")
+            || (lowered.contains("code generated") && lowered.contains("do not edit"))
+            || ((lowered.contains("auto-generated") || lowered.contains("autogenerated"))
+                && lowered.contains("do not"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn category(path: &str, language: &str, sniffed: &[&str]) -> &'static str {
+        FileClassifier::category(&[], Path::new(path), language, sniffed)
+    }
+
+    #[test]
+    fn reads_shebangs() {
+        let shebang = |line: &str| FileClassifier::shebang_language(line);
+        assert_eq!(shebang("#!/bin/bash\necho"), Some("shell".to_string()));
+        assert_eq!(shebang("#!/usr/bin/python3.11"), Some("python".to_string()));
+        assert_eq!(shebang("#!/usr/bin/env perl5"), Some("perl".to_string()));
+        assert_eq!(shebang("#!/usr/bin/env -S node --harmony"), Some("javascript".to_string()));
+        assert_eq!(shebang("#!/usr/bin/env RUBYOPT=-w ruby"), Some("ruby".to_string()));
+        assert_eq!(shebang("print('no shebang')"), None);
+    }
+
+    #[test]
+    fn reads_modelines() {
+        let classifier = FileClassifier::new();
+        let modeline = |line: &str| classifier.modeline_language(&[line]);
+        assert_eq!(modeline("// vim: set ft=ruby :"), Some("ruby".to_string()));
+        assert_eq!(modeline("# vi: filetype=sh"), Some("shell".to_string()));
+        assert_eq!(modeline("# -*- mode: python; coding: utf-8 -*-"), Some("python".to_string()));
+        assert_eq!(modeline("/* -*- C++ -*- */"), Some("cpp".to_string()));
+        assert_eq!(modeline("// just a comment"), None);
+    }
+
+    #[test]
+    fn tells_header_languages_apart() {
+        assert_eq!(FileClassifier::header_language(Some("#import <Foundation/Foundation.h>")), "objective-c");
+        assert_eq!(FileClassifier::header_language(Some("namespace app { class Widget; }")), "cpp");
+        assert_eq!(FileClassifier::header_language(Some("int add(int a, int b);")), "c");
+        assert_eq!(FileClassifier::header_language(None), "c");
+    }
+
+    #[test]
+    fn orders_categories() {
+        // vendored wins over everything, then generated, test, docs and config
+        assert_eq!(category("vendor/lib/foo_test.go", "go", &[]), "vendored");
+        assert_eq!(category("tests/package-lock.json", "json", &[]), "generated");
+        assert_eq!(category("src/api.rs", "rust", &["// @generated by build.rs"]), "generated");
+        assert_eq!(category("docs/test_guide.md", "markdown", &[]), "test");
+        assert_eq!(category("src/WidgetTests.java", "java", &[]), "test");
+        assert_eq!(category("src/Test.java", "java", &[]), "source");
+        assert_eq!(category("README", "None", &[]), "docs");
+        assert_eq!(category("config/app.yml", "yaml", &[]), "config");
+        assert_eq!(category(".eslintrc", "None", &[]), "config");
+        assert_eq!(category("assets/logo.bin", "None", &[]), "other");
+    }
+
+    #[test]
+    fn parses_attribute_lines() {
+        let rules = FileClassifier::parse_attr_file(
+            "# comment\n[attr]binary -diff -merge\ndocs/ linguist-documentation\n*.x linguist-language=Rust -linguist-generated !linguist-vendored\n");
+        assert_eq!(rules.len(), 1);
+        assert!(rules[0].glob.is_match("a/b.x"));
+        let attrs = &rules[0].attrs;
+        assert!(matches!(FileClassifier::attr_state(attrs, "linguist-language"), Some(AttrState::Value(value)) if value == "Rust"));
+        assert_eq!(FileClassifier::attr_flag(attrs, "linguist-generated"), Some(false));
+        assert_eq!(FileClassifier::attr_flag(attrs, "linguist-vendored"), None);
+    }
+
+    #[test]
+    fn applies_attributes_of_the_commit_tree() {
+        let path = env::temp_dir().join(format!("devprofiler-attributes-{}", process::id()));
+        let _res = fs::remove_dir_all(&path);
+        let repo = Repository::init(&path).unwrap();
+        let files = [
+            (".gitattributes", "*.x linguist-language=Rust\nsub/deep/*.x linguist-vendored\n"),
+            ("sub/.gitattributes", "*.x linguist-language=Go\ngen.x linguist-generated\n/docs.x linguist-documentation\n"),
+            ("top.x", "a"), ("sub/s.x", "a"), ("sub/gen.x", "a"), ("sub/docs.x", "a"), ("sub/deep/v.x", "a"),
+        ];
+        for (file, content) in files {
+            let file_path = path.join(file);
+            fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+            fs::write(file_path, content).unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], git2::IndexAddOption::DEFAULT, None).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        // the checkout disagrees, only the tree counts
+        fs::write(path.join(".gitattributes"), "*.x linguist-language=Python\n").unwrap();
+        let classifier = FileClassifier::new();
+        let classify = |file: &str| {
+            let class = classifier.classify(&repo, Some(&tree), Path::new(file), Oid::zero());
+            (class.language, class.category)
+        };
+        assert_eq!(classify("top.x"), ("rust".to_string(), "source"));
+        assert_eq!(classify("sub/s.x"), ("go".to_string(), "source"));
+        assert_eq!(classify("sub/gen.x"), ("go".to_string(), "generated"));
+        assert_eq!(classify("sub/docs.x"), ("go".to_string(), "docs"));
+        assert_eq!(classify("sub/deep/v.x"), ("go".to_string(), "vendored"));
+        assert_eq!(classify("sub/deep/docs.x").1, "vendored");
+        let _res = fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,281 @@
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+use crate::config::Config;
+use crate::analyzer::MergeDiff;
+use crate::anonymizer::LEVELS;
+use crate::writer::OutputFormat;
+
+/// Running without a subcommand starts the interactive mode, which prompts
+/// for the scan path, repositories and aliases. Every subcommand is
+/// non-interactive and never reads from stdin.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+    /// How much leaves the machine: minimal, standard or full
+    /// [config: privacy.level]
+    #[arg(long, global = true, value_parser = LEVELS)]
+    pub privacy: Option<String>,
+    /// Print which fields would be written, with sample values, instead of
+    /// writing or uploading anything [config: output.dry_run]
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+}
+
+impl Cli {
+    pub fn apply(&self, config: &mut Config) {
+        config.set_cli("privacy.level", self.privacy.clone());
+        config.set_cli("output.dry_run", self.dry_run.then_some(true));
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// Scan a path for git repositories and print one path per line
+    Scan(ScanArgs),
+    /// Analyze repositories and write the profile archive
+    Analyze(AnalyzeArgs),
+    /// Compute and upload hunk relevance for open reviews of a repository
+    ReviewHunks(ReviewArgs),
+    /// Run review-hunks followed by analyze for a hosted repository
+    Report(ReportArgs),
+    /// Inspect the effective configuration
+    #[command(subcommand)]
+    Config(ConfigCommands),
+    /// Print the JSON Schema every output record conforms to
+    Schema,
+    /// Decode a JSONL output, validate its records and print counts per
+    /// record type, repository and author hash
+    Inspect(InspectArgs),
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Print every setting with its effective value and where it came from
+    Show,
+}
+
+#[derive(Args)]
+pub struct InspectArgs {
+    /// Output file, gzipped or plain JSONL
+    pub file: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ScanArgs {
+    /// Path scanned for repositories [config: scan.path]
+    #[arg(long)]
+    pub path: Option<PathBuf>,
+    /// Directory levels below the path to look for repositories, 0 for no
+    /// limit [config: scan.max_depth]
+    #[arg(long)]
+    pub max_depth: Option<i64>,
+    /// Only report repositories under directories matching these globs
+    /// (repeatable or comma separated) [config: scan.include]
+    #[arg(long, value_delimiter = ',')]
+    pub scan_include: Vec<String>,
+    /// Skip directories matching these globs, e.g. "~/Library" or "archive"
+    /// (repeatable or comma separated) [config: scan.exclude]
+    #[arg(long, value_delimiter = ',')]
+    pub scan_exclude: Vec<String>,
+    /// Do not cross into other mounted filesystems [config: scan.one_file_system]
+    #[arg(long)]
+    pub one_file_system: bool,
+    /// Follow symlinked directories, each directory is still scanned once
+    /// [config: scan.follow_symlinks]
+    #[arg(long)]
+    pub follow_symlinks: bool,
+}
+
+#[derive(Args)]
+pub struct AnalyzeArgs {
+    #[command(flatten)]
+    pub scan: ScanArgs,
+    /// Repositories to analyze (repeatable or comma separated), skips
+    /// scanning and the options above
+    #[arg(long = "repo", value_delimiter = ',')]
+    pub repos: Vec<String>,
+    /// Email aliases recorded as yours (repeatable or comma separated),
+    /// all aliases found are recorded when omitted
+    #[arg(long = "alias", value_delimiter = ',')]
+    pub aliases: Vec<String>,
+    /// Path of the profile archive, - for stdout, defaults to devprofile.<format extension>
+    /// [config: output.path]
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+    /// Format of the profile archive [config: output.format]
+    #[arg(long, value_parser = OutputFormat::NAMES)]
+    pub format: Option<String>,
+    /// Directory the profile archive is written to, created when missing
+    /// [config: output.dir]
+    #[arg(long)]
+    pub output_dir: Option<PathBuf>,
+    /// Add a UTC timestamp to the archive and io_errors.txt filenames [config: output.timestamp]
+    #[arg(long)]
+    pub timestamp: bool,
+    /// Fail instead of overwriting an existing archive or io_errors.txt [config: output.no_clobber]
+    #[arg(long)]
+    pub no_clobber: bool,
+    /// Analyze the full history, ignoring checkpoints of previous runs
+    /// [config: analysis.incremental = false]
+    #[arg(long)]
+    pub full: bool,
+    /// File storing checkpoints between runs [config: state.path]
+    #[arg(long)]
+    pub state_file: Option<PathBuf>,
+    /// Secret key for hashing identities and paths, created on first use
+    /// [config: privacy.key_file]
+    #[arg(long)]
+    pub key_file: Option<PathBuf>,
+    /// Only analyze commits made at or after this date, e.g. 2024-01-01 or
+    /// "2 weeks ago" [config: analysis.since]
+    #[arg(long)]
+    pub since: Option<String>,
+    /// Only analyze commits made at or before this date, a date without a
+    /// time including that whole day [config: analysis.until]
+    #[arg(long)]
+    pub until: Option<String>,
+    /// Only analyze commits reachable from B but not from A, given as A..B,
+    /// instead of the selected refs [config: analysis.range]
+    #[arg(long)]
+    pub range: Option<String>,
+    /// Only analyze commits by these authors, given as emails, names or
+    /// /regexes/ (repeatable or comma separated) [config: analysis.authors]
+    #[arg(long = "author", value_delimiter = ',')]
+    pub authors: Vec<String>,
+    /// Ignore .mailmap files when resolving identities [config: analysis.use_mailmap = false]
+    #[arg(long)]
+    pub no_mailmap: bool,
+    /// Mailmap overriding and extending each repository's .mailmap
+    /// [config: analysis.mailmap_file]
+    #[arg(long)]
+    pub mailmap: Option<PathBuf>,
+    /// Also analyze all local branches [config: analysis.branches]
+    #[arg(long)]
+    pub branches: bool,
+    /// Also analyze all remote-tracking branches [config: analysis.remotes]
+    #[arg(long)]
+    pub remotes: bool,
+    /// Also analyze all tags [config: analysis.tags]
+    #[arg(long)]
+    pub tags: bool,
+    /// Shorthand for --branches --remotes --tags
+    #[arg(long)]
+    pub all_refs: bool,
+    /// Also analyze these refs, given as revisions or refs/ globs (repeatable
+    /// or comma separated) [config: analysis.refs]
+    #[arg(long = "ref", value_delimiter = ',')]
+    pub refs: Vec<String>,
+    /// Diff merge commits against their first parent, keep only files
+    /// changed against every parent, or skip them [config: analysis.merges]
+    #[arg(long, value_parser = MergeDiff::NAMES)]
+    pub merges: Option<String>,
+    /// Similarity in percent above which files count as renamed or copied,
+    /// 0 disables detection [config: analysis.similarity_threshold]
+    #[arg(long, value_parser = clap::value_parser!(i64).range(0..=100))]
+    pub similarity_threshold: Option<i64>,
+    /// Count generated, vendored and binary files in the stats
+    /// [config: analysis.default_excludes = false]
+    #[arg(long)]
+    pub no_default_excludes: bool,
+    /// Leave files matching these globs out of the stats, e.g. "*.snap" or
+    /// "/assets/" (repeatable or comma separated) [config: analysis.exclude]
+    #[arg(long, value_delimiter = ',')]
+    pub exclude: Vec<String>,
+    /// Repositories analyzed at the same time, 0 for one per CPU
+    /// [config: analysis.jobs]
+    #[arg(long, short = 'j')]
+    pub jobs: Option<i64>,
+    /// Workers diffing the commits of each repository, 0 shares the CPUs
+    /// out between the repositories analyzed at once [config: analysis.diff_jobs]
+    #[arg(long)]
+    pub diff_jobs: Option<i64>,
+}
+
+#[derive(Args)]
+pub struct ReviewArgs {
+    /// Repository hosting provider, e.g. github or bitbucket
+    #[arg(long)]
+    pub provider: String,
+    /// Repository name and owner, e.g. owner/repo
+    #[arg(long)]
+    pub repo_slug: String,
+    /// Files with more changed lines are skipped [config: review.line_threshold]
+    #[arg(long)]
+    pub line_threshold: Option<i64>,
+    /// Endpoint listing open reviews [config: api.tasks_url]
+    #[arg(long)]
+    pub tasks_url: Option<String>,
+    /// Endpoint storing computed hunks [config: api.store_url]
+    #[arg(long)]
+    pub store_url: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ReportArgs {
+    #[command(flatten)]
+    pub review: ReviewArgs,
+    #[command(flatten)]
+    pub analyze: AnalyzeArgs,
+}
+
+fn path_value(path: &Option<PathBuf>) -> Option<String> {
+    path.as_ref().map(|p| p.display().to_string())
+}
+
+impl ScanArgs {
+    pub fn apply(&self, config: &mut Config) {
+        config.set_cli("scan.path", path_value(&self.path));
+        config.set_cli("scan.max_depth", self.max_depth);
+        config.set_cli("scan.include", (!self.scan_include.is_empty()).then(|| self.scan_include.clone()));
+        config.set_cli("scan.exclude", (!self.scan_exclude.is_empty()).then(|| self.scan_exclude.clone()));
+        config.set_cli("scan.one_file_system", self.one_file_system.then_some(true));
+        config.set_cli("scan.follow_symlinks", self.follow_symlinks.then_some(true));
+    }
+}
+
+impl AnalyzeArgs {
+    pub fn apply(&self, config: &mut Config) {
+        self.scan.apply(config);
+        config.set_cli("output.path", path_value(&self.output));
+        config.set_cli("output.format", self.format.clone());
+        config.set_cli("output.dir", path_value(&self.output_dir));
+        config.set_cli("output.timestamp", self.timestamp.then_some(true));
+        config.set_cli("output.no_clobber", self.no_clobber.then_some(true));
+        config.set_cli("analysis.incremental", self.full.then_some(false));
+        config.set_cli("state.path", path_value(&self.state_file));
+        config.set_cli("privacy.key_file", path_value(&self.key_file));
+        config.set_cli("analysis.since", self.since.clone());
+        config.set_cli("analysis.until", self.until.clone());
+        config.set_cli("analysis.range", self.range.clone());
+        config.set_cli("analysis.authors", (!self.authors.is_empty()).then(|| self.authors.clone()));
+        config.set_cli("analysis.use_mailmap", self.no_mailmap.then_some(false));
+        config.set_cli("analysis.mailmap_file", path_value(&self.mailmap));
+        config.set_cli("analysis.branches", (self.branches || self.all_refs).then_some(true));
+        config.set_cli("analysis.remotes", (self.remotes || self.all_refs).then_some(true));
+        config.set_cli("analysis.tags", (self.tags || self.all_refs).then_some(true));
+        config.set_cli("analysis.refs", (!self.refs.is_empty()).then(|| self.refs.clone()));
+        config.set_cli("analysis.merges", self.merges.clone());
+        config.set_cli("analysis.similarity_threshold", self.similarity_threshold);
+        config.set_cli("analysis.default_excludes", self.no_default_excludes.then_some(false));
+        config.set_cli("analysis.exclude", (!self.exclude.is_empty()).then(|| self.exclude.clone()));
+        config.set_cli("analysis.jobs", self.jobs);
+        config.set_cli("analysis.diff_jobs", self.diff_jobs);
+    }
+}
+
+impl ReviewArgs {
+    pub fn apply(&self, config: &mut Config) {
+        config.set_cli("review.line_threshold", self.line_threshold);
+        config.set_cli("api.tasks_url", self.tasks_url.clone());
+        config.set_cli("api.store_url", self.store_url.clone());
+    }
+}
+
+impl ReportArgs {
+    pub fn apply(&self, config: &mut Config) {
+        self.review.apply(config);
+        self.analyze.apply(config);
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+const CONFIG_FILENAME: &str = "devprofiler.toml";
+const ENV_PREFIX: &str = "DEVPROFILER_";
+
+#[derive(Clone, Debug)]
+pub enum ConfigSource {
+    Default,
+    System(PathBuf),
+    User(PathBuf),
+    Repo(PathBuf),
+    Env(String),
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::System(path) => write!(f, "system config {}", path.display()),
+            ConfigSource::User(path) => write!(f, "user config {}", path.display()),
+            ConfigSource::Repo(path) => write!(f, "repo config {}", path.display()),
+            ConfigSource::Env(name) => write!(f, "env {name}"),
+            ConfigSource::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// Effective settings, merged from defaults, config files, environment
+/// variables and command line flags in increasing order of precedence.
+pub struct Config {
+    values: BTreeMap<String, (Value, ConfigSource)>,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, Box<dyn Error>> {
+        let mut config = Self::defaults();
+        let mut layers = vec![ConfigSource::System(PathBuf::from("/etc/devprofiler").join(CONFIG_FILENAME))];
+        if let Some(user_dir) = Self::user_config_dir() {
+            layers.push(ConfigSource::User(user_dir.join("devprofiler").join(CONFIG_FILENAME)));
+        }
+        layers.push(ConfigSource::Repo(PathBuf::from(CONFIG_FILENAME)));
+        for layer in layers {
+            config.merge_file(layer)?;
+        }
+        config.merge_env()?;
+        Ok(config)
+    }
+
+    pub(crate) fn defaults() -> Self {
+        let defaults: Vec<(&str, Value)> = vec![
+            ("scan.path", Value::from("/")),
+            ("scan.max_depth", Value::from(0)),
+            ("scan.include", Value::Array(Vec::new())),
+            ("scan.exclude", Value::Array(Vec::new())),
+            ("scan.one_file_system", Value::from(false)),
+            ("scan.follow_symlinks", Value::from(false)),
+            ("scan.prune", Value::from(vec![
+                "node_modules", "target", "bower_components", ".venv", "venv", "__pycache__",
+                ".tox", ".cache", ".npm", ".yarn", ".cargo", ".rustup", ".gradle", ".m2",
+                ".terraform", ".Trash",
+            ])),
+            ("output.path", Value::from("")),
+            ("output.format", Value::from("jsonl-gz")),
+            ("output.dir", Value::from("")),
+            ("output.timestamp", Value::from(false)),
+            ("output.no_clobber", Value::from(false)),
+            ("output.dry_run", Value::from(false)),
+            ("analysis.incremental", Value::from(true)),
+            ("analysis.since", Value::from("")),
+            ("analysis.until", Value::from("")),
+            ("analysis.range", Value::from("")),
+            ("analysis.authors", Value::Array(Vec::new())),
+            ("analysis.use_mailmap", Value::from(true)),
+            ("analysis.mailmap_file", Value::from("")),
+            ("analysis.branches", Value::from(false)),
+            ("analysis.remotes", Value::from(false)),
+            ("analysis.tags", Value::from(false)),
+            ("analysis.refs", Value::Array(Vec::new())),
+            ("analysis.merges", Value::from("first-parent")),
+            ("analysis.similarity_threshold", Value::from(50)),
+            ("analysis.default_excludes", Value::from(true)),
+            ("analysis.exclude", Value::Array(Vec::new())),
+            ("analysis.jobs", Value::from(0)),
+            ("analysis.diff_jobs", Value::from(0)),
+            ("state.path", Value::from("")),
+            ("privacy.level", Value::from("standard")),
+            ("privacy.key_file", Value::from("")),
+            ("privacy.fields.commit_id", Value::from("")),
+            ("privacy.fields.repo_name", Value::from("")),
+            ("privacy.fields.refs", Value::from("")),
+            ("privacy.fields.author_name", Value::from("")),
+            ("privacy.fields.author_email", Value::from("")),
+            ("privacy.fields.committer_name", Value::from("")),
+            ("privacy.fields.committer_email", Value::from("")),
+            ("privacy.fields.co_authors", Value::from("")),
+            ("privacy.fields.path", Value::from("")),
+            ("privacy.fields.filename", Value::from("")),
+            ("privacy.fields.extension", Value::from("")),
+            ("privacy.fields.aliases", Value::from("")),
+            ("privacy.fields.blame_author", Value::from("")),
+            ("review.line_threshold", Value::from(500)),
+            ("api.tasks_url", Value::from("https://gcscruncsql-k7jns52mtq-el.a.run.app/relevance/hunk")),
+            ("api.store_url", Value::from("https://gcscruncsql-k7jns52mtq-el.a.run.app/relevance/hunk/store")),
+        ];
+        let mut values = BTreeMap::new();
+        for (key, value) in defaults {
+            values.insert(key.to_string(), (value, ConfigSource::Default));
+        }
+        Self { values }
+    }
+
+    pub fn user_config_dir() -> Option<PathBuf> {
+        match env::var_os("XDG_CONFIG_HOME") {
+            Some(xdg_dir) => Some(PathBuf::from(xdg_dir)),
+            None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+        }
+    }
+
+    fn merge_file(&mut self, source: ConfigSource) -> Result<(), Box<dyn Error>> {
+        let path = match &source {
+            ConfigSource::System(path) | ConfigSource::User(path) | ConfigSource::Repo(path) => path,
+            _ => return Ok(()),
+        };
+        if !path.is_file() {
+            return Ok(());
+        }
+        let table: Table = fs::read_to_string(path)?.parse()
+            .map_err(|err| format!("Unable to parse {} : {err}", path.display()))?;
+        let mut flat = Vec::<(String, Value)>::new();
+        Self::flatten("", table, &mut flat);
+        for (key, value) in flat {
+            self.set(&key, value, source.clone())
+                .map_err(|err| format!("{err} in {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn flatten(prefix: &str, table: Table, flat: &mut Vec<(String, Value)>) {
+        for (key, value) in table {
+            let full_key = match prefix.is_empty() {
+                true => key,
+                false => format!("{prefix}.{key}"),
+            };
+            match value {
+                Value::Table(subtable) => Self::flatten(&full_key, subtable, flat),
+                _ => flat.push((full_key, value)),
+            }
+        }
+    }
+
+    fn merge_env(&mut self) -> Result<(), Box<dyn Error>> {
+        let keys: Vec<String> = self.values.keys().cloned().collect();
+        for key in keys {
+            let env_name = Self::env_name(&key);
+            if let Ok(env_val) = env::var(&env_name) {
+                let value = self.parse_as(&key, &env_val)
+                    .map_err(|err| format!("{err} in {env_name}"))?;
+                self.set(&key, value, ConfigSource::Env(env_name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn env_name(key: &str) -> String {
+        ENV_PREFIX.to_string() + &key.replace('.', "_").to_uppercase()
+    }
+
+    /// Parses a plain string into the type of the current value of `key`.
+    /// Lists are comma separated.
+    fn parse_as(&self, key: &str, raw: &str) -> Result<Value, String> {
+        let invalid = || format!("Invalid value {raw:?} for {key}");
+        match self.values.get(key) {
+            Some((Value::Integer(_), _)) => raw.trim().parse::<i64>()
+                .map(Value::from).map_err(|_| invalid()),
+            Some((Value::Boolean(_), _)) => raw.trim().parse::<bool>()
+                .map(Value::from).map_err(|_| invalid()),
+            Some((Value::Array(_), _)) => Ok(Value::from(
+                raw.split(',').map(str::trim).filter(|item| !item.is_empty())
+                    .map(str::to_string).collect::<Vec<String>>())),
+            Some(_) => Ok(Value::from(raw)),
+            None => Err(format!("Unknown config key {key}")),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: Value, source: ConfigSource) -> Result<(), String> {
+        match self.values.get_mut(key) {
+            Some(entry) => {
+                if entry.0.type_str() != value.type_str() {
+                    return Err(format!("Expected {} for {key}, found {}",
+                        entry.0.type_str(), value.type_str()));
+                }
+                *entry = (value, source);
+                Ok(())
+            }
+            None => Err(format!("Unknown config key {key}")),
+        }
+    }
+
+    /// Applies a command line flag, if it was given, on top of all other layers.
+    pub fn set_cli<T: Into<Value>>(&mut self, key: &str, value: Option<T>) {
+        if let Some(cli_val) = value {
+            self.set(key, cli_val.into(), ConfigSource::Cli)
+                .expect("Command line flags map to known keys of the same type");
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> String {
+        match &self.values[key].0 {
+            Value::String(val) => val.to_string(),
+            other => other.to_string(),
+        }
+    }
+
+    pub fn get_int(&self, key: &str) -> i64 {
+        self.values[key].0.as_integer().expect("Type checked on set")
+    }
+
+    pub fn get_bool(&self, key: &str) -> bool {
+        self.values[key].0.as_bool().expect("Type checked on set")
+    }
+
+    pub fn get_list(&self, key: &str) -> Vec<String> {
+        self.values[key].0.as_array().expect("Type checked on set").iter()
+            .map(|item| match item {
+                Value::String(val) => val.to_string(),
+                other => other.to_string(),
+            })
+            .collect()
+    }
+
+    pub fn show(&self) {
+        for (key, (value, source)) in &self.values {
+            println!("{key} = {value}  # {source}");
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::Path;
+use crate::classifier::FileClass;
+use crate::config::Config;
+
+/// Decides which changed files are left out of contribution stats.
+#[derive(Clone)]
+pub struct ExcludeRules {
+    /// Built-in defaults: generated and vendored files, including those
+    /// marked in `.gitattributes`, and binary files
+    defaults: bool,
+    globs: GlobSet,
+}
+
+/// Anchors a pattern the way `.gitignore` does, relative to the directory it
+/// applies to: without an inner slash it matches at any depth below, otherwise
+/// from that directory. Leading and trailing slashes are dropped.
+pub fn anchor_pattern(pattern: &str) -> String {
+    let trimmed = pattern.trim_end_matches('/');
+    match (trimmed.strip_prefix('/'), trimmed.contains('/')) {
+        (Some(rooted), _) => rooted.to_string(),
+        (None, false) => format!("**/{trimmed}"),
+        (None, true) => trimmed.to_string(),
+    }
+}
+
+impl ExcludeRules {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in config.get_list("analysis.exclude") {
+            // anchored at the repository root
+            let anchored = anchor_pattern(&pattern);
+            let glob = Self::glob(&anchored)
+                .map_err(|err| format!("Invalid exclude pattern {pattern} : {err}"))?;
+            builder.add(glob);
+            // a directory pattern excludes everything below it
+            if let Ok(dir_glob) = Self::glob(&format!("{anchored}/**")) {
+                builder.add(dir_glob);
+            }
+        }
+        Ok(Self {
+            defaults: config.get_bool("analysis.default_excludes"),
+            globs: builder.build().map_err(|err| format!("Invalid exclude patterns : {err}"))?,
+        })
+    }
+
+    /// `*` stays within one path component, as in git.
+    fn glob(pattern: &str) -> Result<Glob, globset::Error> {
+        GlobBuilder::new(pattern).literal_separator(true).build()
+    }
+
+    pub fn is_excluded(&self, path: &Path, class: &FileClass, is_binary: bool) -> bool {
+        if self.globs.is_match(path) {
+            return true;
+        }
+        self.defaults && (is_binary || matches!(class.category, "generated" | "vendored"))
+    }
+}
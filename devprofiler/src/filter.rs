@@ -0,0 +1,218 @@
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+use regex::Regex;
+use crate::config::Config;
+
+/// An author given up front, matched against both the name and the email.
+#[derive(Clone, Debug)]
+enum AuthorPattern {
+    /// Case-insensitive exact match
+    Exact(String),
+    /// Written as `/regex/`
+    Regex(Regex),
+}
+
+impl AuthorPattern {
+    fn parse(pattern: &str) -> Result<Self, String> {
+        match pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+            true => Regex::new(&pattern[1..pattern.len() - 1])
+                .map(AuthorPattern::Regex)
+                .map_err(|err| format!("Invalid author regex {pattern} : {err}")),
+            false => Ok(AuthorPattern::Exact(pattern.to_lowercase())),
+        }
+    }
+
+    fn matches(&self, name: &str, email: &str) -> bool {
+        match self {
+            AuthorPattern::Exact(exact) => name.to_lowercase() == *exact || email.to_lowercase() == *exact,
+            AuthorPattern::Regex(regex) => regex.is_match(name) || regex.is_match(email),
+        }
+    }
+}
+
+/// Restricts the commits emitted by the analyzer. Times are compared
+/// against the author time, the same value recorded in `CommitInfo::ts_secs`.
+#[derive(Clone, Debug, Default)]
+pub struct CommitFilter {
+    since: Option<i64>,
+    until: Option<i64>,
+    range: Option<(String, String)>,
+    authors: Vec<AuthorPattern>,
+}
+
+impl CommitFilter {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let now = Utc::now();
+        let since = match config.get_str("analysis.since") {
+            since_str if since_str.is_empty() => None,
+            since_str => Some(parse_date(&since_str, now, false)?),
+        };
+        let until = match config.get_str("analysis.until") {
+            until_str if until_str.is_empty() => None,
+            until_str => Some(parse_date(&until_str, now, true)?),
+        };
+        let range = match config.get_str("analysis.range") {
+            range_str if range_str.is_empty() => None,
+            range_str => Some(parse_range(&range_str)?),
+        };
+        let authors = config.get_list("analysis.authors").iter()
+            .map(|pattern| AuthorPattern::parse(pattern))
+            .collect::<Result<Vec<AuthorPattern>, String>>()?;
+        Ok(Self { since, until, range, authors })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.since.is_some() || self.until.is_some() || self.range.is_some()
+            || !self.authors.is_empty()
+    }
+
+    pub fn range(&self) -> Option<&(String, String)> {
+        self.range.as_ref()
+    }
+
+    pub fn matches_time(&self, ts_secs: i64) -> bool {
+        self.since.is_none_or(|since| ts_secs >= since)
+            && self.until.is_none_or(|until| ts_secs <= until)
+    }
+
+    /// True when no authors were given or any of them matches.
+    pub fn matches_author(&self, name: &str, email: &str) -> bool {
+        self.authors.is_empty() || self.authors.iter().any(|pattern| pattern.matches(name, email))
+    }
+}
+
+/// Splits `A..B` into its two revisions, an empty side meaning HEAD as in git.
+fn parse_range(range_str: &str) -> Result<(String, String), String> {
+    if range_str.contains("...") {
+        return Err(format!("Symmetric range {range_str} is not supported, use A..B"));
+    }
+    match range_str.split_once("..") {
+        Some((from, to)) => {
+            let or_head = |rev: &str| match rev.is_empty() {
+                true => "HEAD".to_string(),
+                false => rev.to_string(),
+            };
+            Ok((or_head(from), or_head(to)))
+        },
+        None => Err(format!("Invalid range {range_str}, expected A..B")),
+    }
+}
+
+/// Parses an absolute date (`2024-03-01`, `2024-03-01 12:00`, RFC 3339) or a
+/// relative one (`now`, `today`, `yesterday`, `3 weeks ago`) into unix seconds.
+/// Dates without a timezone are taken as UTC. A day given without a time
+/// starts at midnight, or ends just before the next one with `end_of_day`,
+/// so that an until date includes the commits of that day.
+fn parse_date(date_str: &str, now: DateTime<Utc>, end_of_day: bool) -> Result<i64, String> {
+    let day_bound = |midnight: i64| match end_of_day {
+        true => midnight + 86_400 - 1,
+        false => midnight,
+    };
+    let trimmed = date_str.trim();
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(trimmed) {
+        return Ok(datetime.timestamp());
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(trimmed, format) {
+            return Ok(datetime.and_utc().timestamp());
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(trimmed, "%Y-%m-%d") {
+        return Ok(day_bound(date.and_hms_opt(0, 0, 0).expect("Midnight is valid").and_utc().timestamp()));
+    }
+    let midnight = now.date_naive().and_hms_opt(0, 0, 0).expect("Midnight is valid").and_utc();
+    match trimmed.to_lowercase().as_str() {
+        "now" => return Ok(now.timestamp()),
+        "today" => return Ok(day_bound(midnight.timestamp())),
+        "yesterday" => return Ok(day_bound((midnight - Duration::days(1)).timestamp())),
+        _ => {}
+    }
+    let invalid = || format!("Invalid date {date_str:?}, expected YYYY-MM-DD or e.g. \"2 weeks ago\"");
+    let words: Vec<String> = trimmed.to_lowercase().split_whitespace().map(str::to_string).collect();
+    let (count, unit) = match words.as_slice() {
+        [count, unit] => (count, unit),
+        [count, unit, ago] if ago == "ago" => (count, unit),
+        _ => return Err(invalid()),
+    };
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let unit_secs = match unit.trim_end_matches('s') {
+        "second" | "sec" => 1,
+        "minute" | "min" => 60,
+        "hour" => 3_600,
+        "day" => 86_400,
+        "week" => 7 * 86_400,
+        "month" => 30 * 86_400,
+        "year" => 365 * 86_400,
+        _ => return Err(invalid()),
+    };
+    Ok(now.timestamp() - count * unit_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-03-10T15:30:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_absolute_dates() {
+        let midnight = DateTime::parse_from_rfc3339("2024-03-01T00:00:00Z").unwrap().timestamp();
+        assert_eq!(parse_date("2024-03-01", now(), false), Ok(midnight));
+        assert_eq!(parse_date(" 2024-03-01 12:00 ", now(), false), Ok(midnight + 12 * 3_600));
+        assert_eq!(parse_date("2024-03-01T12:00:30", now(), false), Ok(midnight + 12 * 3_600 + 30));
+        assert_eq!(parse_date("2024-03-01T12:00:00+02:00", now(), false), Ok(midnight + 10 * 3_600));
+    }
+
+    #[test]
+    fn ends_date_only_values_at_the_end_of_the_day() {
+        let midnight = DateTime::parse_from_rfc3339("2024-03-31T00:00:00Z").unwrap().timestamp();
+        let today = DateTime::parse_from_rfc3339("2024-03-10T00:00:00Z").unwrap().timestamp();
+        assert_eq!(parse_date("2024-03-31", now(), true), Ok(midnight + 86_399));
+        assert_eq!(parse_date("2024-03-31 12:00", now(), true), Ok(midnight + 12 * 3_600));
+        assert_eq!(parse_date("today", now(), true), Ok(today + 86_399));
+        assert_eq!(parse_date("yesterday", now(), true), Ok(today - 1));
+        assert_eq!(parse_date("2 days ago", now(), true), Ok(now().timestamp() - 2 * 86_400));
+    }
+
+    #[test]
+    fn parses_relative_dates() {
+        let now_secs = now().timestamp();
+        let midnight = DateTime::parse_from_rfc3339("2024-03-10T00:00:00Z").unwrap().timestamp();
+        assert_eq!(parse_date("now", now(), false), Ok(now_secs));
+        assert_eq!(parse_date("Today", now(), false), Ok(midnight));
+        assert_eq!(parse_date("yesterday", now(), false), Ok(midnight - 86_400));
+        assert_eq!(parse_date("3 weeks ago", now(), false), Ok(now_secs - 21 * 86_400));
+        assert_eq!(parse_date("1 day", now(), false), Ok(now_secs - 86_400));
+        assert_eq!(parse_date("2 hours ago", now(), false), Ok(now_secs - 7_200));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        assert!(parse_date("last tuesday", now(), false).is_err());
+        assert!(parse_date("3 fortnights ago", now(), false).is_err());
+        assert!(parse_date("2024-13-01", now(), false).is_err());
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("v1.0..v2.0"), Ok(("v1.0".to_string(), "v2.0".to_string())));
+        assert_eq!(parse_range("v1.0.."), Ok(("v1.0".to_string(), "HEAD".to_string())));
+        assert_eq!(parse_range("..main"), Ok(("HEAD".to_string(), "main".to_string())));
+        assert!(parse_range("main...topic").is_err());
+        assert!(parse_range("main").is_err());
+    }
+
+    #[test]
+    fn parses_author_patterns() {
+        let exact = AuthorPattern::parse("Jane@Example.com").unwrap();
+        assert!(exact.matches("Jane Doe", "jane@example.com"));
+        assert!(!exact.matches("Jane Doe", "jane@example.org"));
+        let regex = AuthorPattern::parse("/^jane@/").unwrap();
+        assert!(matches!(regex, AuthorPattern::Regex(_)));
+        assert!(regex.matches("Jane Doe", "jane@example.org"));
+        assert!(!regex.matches("John Doe", "john@example.org"));
+        assert!(matches!(AuthorPattern::parse("/").unwrap(), AuthorPattern::Exact(_)));
+        assert!(AuthorPattern::parse("/(/").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use flate2::read::MultiGzDecoder;
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::path::Path;
+use crate::writer::{self, SCHEMA_VERSION};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Problems of each kind printed before only counting the rest.
+const MAX_REPORTED: usize = 10;
+
+/// Counts gathered while reading back a JSONL output.
+#[derive(Default)]
+struct Summary {
+    records: usize,
+    per_type: BTreeMap<String, usize>,
+    per_repo: BTreeMap<String, usize>,
+    per_author: BTreeMap<String, usize>,
+    invalid_json: usize,
+    invalid_records: usize,
+    schema_version: Option<u64>,
+}
+
+impl Summary {
+    fn print(&self) {
+        println!("records: {}", self.records);
+        match self.schema_version {
+            Some(version) => println!("schema_version: {version}"),
+            None => println!("schema_version: missing"),
+        }
+        Self::print_counts("types", &self.per_type);
+        Self::print_counts("repositories", &self.per_repo);
+        Self::print_counts("author hashes", &self.per_author);
+        println!("invalid json lines: {}", self.invalid_json);
+        println!("records failing the schema: {}", self.invalid_records);
+    }
+
+    fn print_counts(title: &str, counts: &BTreeMap<String, usize>) {
+        println!("{title}:");
+        for (key, count) in counts {
+            println!("  {key}  {count}");
+        }
+    }
+}
+
+/// Decodes a JSONL output, gzipped or plain, validates every record against
+/// the record schema and prints a summary. Returns false when anything was
+/// invalid, truncated or corrupt.
+pub fn inspect(path: &Path) -> Result<bool, Box<dyn Error>> {
+    let schemas = variant_schemas()?;
+    let mut file = BufReader::new(File::open(path)?);
+    let is_gzip = file.fill_buf()?.starts_with(&GZIP_MAGIC);
+    let reader: Box<dyn BufRead> = match is_gzip {
+        true => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        false => Box::new(file),
+    };
+    let mut summary = Summary::default();
+    let stream_ok = read_records(reader, &schemas, &mut summary);
+    summary.print();
+    if summary.schema_version.is_some_and(|version| version != u64::from(SCHEMA_VERSION)) {
+        eprintln!("Written with schema version {}, this devprofiler validates version {SCHEMA_VERSION}",
+            summary.schema_version.unwrap_or_default());
+    }
+    Ok(stream_ok && summary.invalid_json == 0 && summary.invalid_records == 0
+        && summary.schema_version.is_some())
+}
+
+/// Splits the record schema into one validator per record type, so a bad
+/// field is reported as such rather than as a record matching no variant.
+fn variant_schemas() -> Result<BTreeMap<String, JSONSchema>, Box<dyn Error>> {
+    let mut root = serde_json::to_value(writer::record_schema())?;
+    let variants = match root.as_object_mut().and_then(|root| root.remove("oneOf")) {
+        Some(Value::Array(variants)) => variants,
+        _ => return Err("Record schema has no record types".into()),
+    };
+    let mut schemas = BTreeMap::new();
+    for variant in variants {
+        let Some(record_type) = variant["properties"]["type"]["enum"][0].as_str() else {
+            return Err("Record schema has a variant without a type".into());
+        };
+        let record_type = record_type.to_string();
+        let mut schema = root.clone();
+        if let (Some(schema), Value::Object(fields)) = (schema.as_object_mut(), variant) {
+            schema.extend(fields);
+        }
+        let compiled = JSONSchema::compile(&schema)
+            .map_err(|err| format!("Invalid schema for {record_type} records : {err}"))?;
+        schemas.insert(record_type, compiled);
+    }
+    Ok(schemas)
+}
+
+/// Reads records until the end of the stream, returning false when it
+/// ended in a truncated or corrupt gzip member.
+fn read_records(mut reader: Box<dyn BufRead>, schemas: &BTreeMap<String, JSONSchema>, summary: &mut Summary) -> bool {
+    let mut line_bytes = Vec::<u8>::new();
+    let mut line_num = 0;
+    loop {
+        line_bytes.clear();
+        match reader.read_until(b'\n', &mut line_bytes) {
+            Ok(0) => return true,
+            Ok(_) => {},
+            Err(err) => {
+                let problem = match err.kind() {
+                    ErrorKind::UnexpectedEof => "Truncated",
+                    _ => "Corrupt",
+                };
+                eprintln!("{problem} gzip stream after line {line_num} : {err}");
+                return false;
+            }
+        }
+        line_num += 1;
+        let line = String::from_utf8_lossy(&line_bytes);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Value = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(err) => {
+                summary.invalid_json += 1;
+                if summary.invalid_json <= MAX_REPORTED {
+                    eprintln!("Line {line_num} is not valid JSON : {err}");
+                }
+                continue;
+            }
+        };
+        let problems: Vec<String> = match record["type"].as_str().and_then(|record_type| schemas.get(record_type)) {
+            Some(schema) => match schema.validate(&record) {
+                Ok(_) => Vec::new(),
+                Err(errors) => errors.map(|error| format!("at {} : {error}", error.instance_path)).collect(),
+            },
+            None => vec![format!("has unknown type {}", record["type"])],
+        };
+        if !problems.is_empty() {
+            summary.invalid_records += 1;
+            if summary.invalid_records <= MAX_REPORTED {
+                for problem in problems {
+                    eprintln!("Line {line_num} {problem}");
+                }
+            }
+        }
+        count_record(&record, line_num, summary);
+    }
+}
+
+fn count_record(record: &Value, line_num: usize, summary: &mut Summary) {
+    summary.records += 1;
+    let record_type = record["type"].as_str().unwrap_or("untyped");
+    *summary.per_type.entry(record_type.to_string()).or_default() += 1;
+    match record_type {
+        "header" if line_num == 1 => summary.schema_version = record["schema_version"].as_u64(),
+        "header" => eprintln!("Line {line_num} repeats the header"),
+        "commit" => {
+            let repo = record["repo_name"].as_str().unwrap_or("(dropped)");
+            *summary.per_repo.entry(repo.to_string()).or_default() += 1;
+            let author = record["author_email"].as_str().unwrap_or("(dropped)");
+            *summary.per_author.entry(author.to_string()).or_default() += 1;
+        },
+        _ => {},
+    }
+}
@@ -1,69 +1,171 @@
+mod cli;
+use crate::cli::{Cli, Commands, ConfigCommands, AnalyzeArgs, InspectArgs, ReviewArgs, ReportArgs};
+mod config;
+use crate::config::Config;
+mod anonymizer;
+use crate::anonymizer::Anonymizer;
+mod checkpoint;
+mod filter;
+mod inspect;
+use crate::checkpoint::{CheckpointStore, RepoCheckpoint};
 mod reader;
 use crate::reader::UserInput;
+mod classifier;
+mod exclude;
 mod analyzer;
-use crate::analyzer::RepoAnalyzer;
+use crate::analyzer::{AnalyzerOptions, CommitInfo, RepoAnalyzer, UserAlias};
 mod writer;
-use crate::writer::OutputWriter;
+use crate::writer::{ChannelWriter, OutputWriter, IoErrWriter, Record};
 mod observer;
-use crate::observer::RuntimeInfo;
+use crate::observer::{Progress, RuntimeInfo};
 mod scanner;
 use crate::scanner::RepoScanner;
 mod reviewer;
 use crate::reviewer::unfinished_tasks;
 use std::process;
-use std::path::Path;
-use serde::{Serialize};
-use std::collections::HashSet;
-use std::io::Write;
-use std::io;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
 use clap::Parser;
-use std::path::PathBuf;
 
+/// Records buffered per worker before analysis waits for the writer.
+const CHANNEL_DEPTH: usize = 256;
 
-#[derive(Parser)]
-struct Cli {
-    /// Specify arg parsing mode for cli
-    provider: Option<String>,
-	/// path scanned for repositories
-    path: Option<PathBuf>,
-	//// repository name and owner
-	repo_slug: Option<String>,
+/// Sent from analysis workers to the thread writing the output, tagged with
+/// the index of the repository.
+enum WorkerMsg {
+	Commit(usize, Box<CommitInfo>),
+	Done(usize, Box<RepoOutcome>),
 }
 
-#[derive(Debug, Serialize, Default)]
-struct UserAlias {
-	alias: Vec::<String>
+impl WorkerMsg {
+	fn repo_idx(&self) -> usize {
+		match self {
+			WorkerMsg::Commit(idx, _) | WorkerMsg::Done(idx, _) => *idx,
+		}
+	}
+}
+
+impl From<(usize, CommitInfo)> for WorkerMsg {
+	fn from((idx, commit): (usize, CommitInfo)) -> Self {
+		WorkerMsg::Commit(idx, Box::new(commit))
+	}
+}
+
+struct RepoOutcome {
+	path: String,
+	einfo: RuntimeInfo,
+	/// No checkpoint when some commit could not be read or written
+	result: Result<(String, HashSet<String>, Option<RepoCheckpoint>), RepoError>,
 }
 
-fn process_repos(user_paths: Vec::<String>, einfo: &mut RuntimeInfo, writer: &mut OutputWriter, repo_slug: Option<String>, provider: Option<String>) -> Vec::<String> {
+enum RepoError {
+	Parse(String),
+	Analyze(String),
+}
+
+fn analyze_repo(path: String, writer: &mut dyn OutputWriter, checkpoints: &CheckpointStore, repo_slug: &Option<String>, provider: &Option<String>, options: &AnalyzerOptions) -> RepoOutcome {
+	let mut einfo = RuntimeInfo::new();
+	let result = match RepoAnalyzer::new(path.as_str(), repo_slug, provider, options) {
+		Ok(ranalyzer) => {
+			let repo_key = ranalyzer.repo_key();
+			let previous = match options.incremental && options.uses_checkpoints() {
+				true => checkpoints.get(&repo_key),
+				false => None,
+			};
+			ranalyzer.analyze(writer, &mut einfo, previous)
+				.map(|(aliases, checkpoint)| (repo_key, aliases, checkpoint))
+				.map_err(|anal_err| RepoError::Analyze(anal_err.to_string()))
+		},
+		Err(ranalyzer_err) => Err(RepoError::Parse(ranalyzer_err.to_string())),
+	};
+	RepoOutcome { path, einfo, result }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_repos(user_paths: Vec::<String>, einfo: &mut RuntimeInfo, writer: &mut dyn OutputWriter, checkpoints: &mut CheckpointStore, repo_slug: Option<String>, provider: Option<String>, options: &AnalyzerOptions, progress: Progress) -> Vec::<String> {
 	let mut valid_repo = 0;
-	let mut all_aliases = HashSet::<String>::new();
+	let mut all_aliases = BTreeSet::<String>::new();
 	let num_user_path = user_paths.len();
-	let mut count = 0;
-	// TODO - optimize count and iterating of vector user_path, get index in for loop
-	for p in user_paths {
-		count += 1;
-		print!("Scanning [{count}/{num_user_path}] \r");
-		let _res = io::stdout().flush();
-		let ranalyzer_res = RepoAnalyzer::new(p.as_str().as_ref(), &repo_slug, &provider);
-		match ranalyzer_res {
-			Ok(ranalyzer) => {
-				valid_repo += 1;
-				let anal_res = ranalyzer.analyze(writer, einfo);
-				match anal_res {
-					Ok(aliases) => { all_aliases.extend(aliases); },
-					Err(anal_err) => {
-						einfo.record_err(anal_err
-							.to_string().as_str().as_ref());
+	let jobs = options.jobs.clamp(1, num_user_path.max(1));
+	let options = &options.for_jobs(jobs);
+	let next_path = AtomicUsize::new(0);
+	let mut updates = Vec::<(String, RepoCheckpoint)>::new();
+	// repositories with commits the writer failed on keep their old checkpoint
+	let mut write_failed = HashSet::<usize>::new();
+	// messages of repositories after the one being written, by repository index
+	let mut held = BTreeMap::<usize, Vec<WorkerMsg>>::new();
+	let mut next_idx = 0;
+	let (sender, receiver) = mpsc::sync_channel::<WorkerMsg>(jobs * CHANNEL_DEPTH);
+	thread::scope(|scope| {
+		let shared_checkpoints: &CheckpointStore = checkpoints;
+		for _ in 0..jobs {
+			let sender = sender.clone();
+			let (next_path, user_paths, repo_slug, provider) = (&next_path, &user_paths, &repo_slug, &provider);
+			scope.spawn(move || loop {
+				let idx = next_path.fetch_add(1, Ordering::Relaxed);
+				let Some(p) = user_paths.get(idx) else { break };
+				let mut worker_writer = ChannelWriter::new(sender.clone(), idx);
+				let outcome = analyze_repo(p.to_string(), &mut worker_writer, shared_checkpoints, repo_slug, provider, options);
+				if sender.send(WorkerMsg::Done(idx, Box::new(outcome))).is_err() {
+					break;
+				}
+			});
+		}
+		// the workers hold the only senders left, ending the loop once they finish
+		drop(sender);
+		// repositories are written in index order, so that runs over the same
+		// repositories produce the same output whatever the scheduling
+		for msg in receiver {
+			held.entry(msg.repo_idx()).or_default().push(msg);
+			while let Some(msgs) = held.remove(&next_idx) {
+				for msg in msgs {
+					let (idx, outcome) = match msg {
+						WorkerMsg::Commit(idx, cinfo) => {
+							if let Err(writer_err) = writer.write_record(&Record::Commit(&cinfo)) {
+								write_failed.insert(idx);
+								einfo.record_err(writer_err.to_string().as_str());
+							}
+							continue;
+						},
+						WorkerMsg::Done(idx, outcome) => (idx, *outcome),
+					};
+					next_idx += 1;
+					progress.print(&format!("Scanning [{next_idx}/{num_user_path}] "));
+					einfo.merge(outcome.einfo);
+					let p = outcome.path;
+					match outcome.result {
+						Ok((repo_key, aliases, checkpoint)) => {
+							valid_repo += 1;
+							all_aliases.extend(aliases);
+							if options.uses_checkpoints() {
+								match checkpoint.filter(|_| !write_failed.contains(&idx)) {
+									Some(checkpoint) => updates.push((repo_key, checkpoint)),
+									None => {
+										eprintln!("Unable to read or write every commit of {p}, its checkpoint is left unchanged");
+										einfo.record_err("Unable to read or write every commit of a repository, its checkpoint is left unchanged");
+									}
+								}
+							}
+						},
+						Err(RepoError::Analyze(anal_err)) => {
+							valid_repo += 1;
+							eprintln!("Unable to analyze {p} due to error : {anal_err}");
+							einfo.record_err(&anal_err);
+						},
+						Err(RepoError::Parse(ranalyzer_err)) => {
+							eprintln!("Unable to parse {p} due to error : {ranalyzer_err}");
+							einfo.record_err(&ranalyzer_err);
+						}
 					}
 				}
-			},
-			Err(ranalyzer_err) => {
-				eprintln!("Unable to parse {p} due to error : {ranalyzer_err}");
-				einfo.record_err(ranalyzer_err
-					.to_string().as_str().as_ref());
 			}
 		}
+	});
+	for (repo_key, checkpoint) in updates {
+		checkpoints.update(&repo_key, checkpoint);
 	}
 	if valid_repo == 0 {
 		let err_line = "Unable to parse any provided repo(s)";
@@ -71,127 +173,248 @@ fn process_repos(user_paths: Vec::<String>, einfo: &mut RuntimeInfo, writer: &mu
 		einfo.record_err(err_line);
 		process::exit(1);
 	}
-	let alias_vec = all_aliases.into_iter().collect();
-	alias_vec
-}
-
-fn process_aliases(alias_vec: Vec::<String>, einfo: &mut RuntimeInfo, writer: &mut OutputWriter, dockermode: bool) {
-	match dockermode {
-		true => {
-			let alias_obj = UserAlias{ alias: alias_vec };
-			let alias_str = serde_json::to_string(&alias_obj).unwrap_or_default();
-			match writer.writeln(alias_str.as_str().as_ref()) {
-				Ok(_) => {},
-				Err(writer_err) => {
-					eprintln!("Unable to record user aliases in output file : {writer_err}");
-					einfo.record_err(writer_err.to_string().as_str().as_ref());
-					let _res = writer.finish(); // result doesn't matter since already in error
-					process::exit(1);
-				}
-			}
+	all_aliases.into_iter().collect()
+}
+
+fn select_aliases(alias_vec: Vec::<String>, requested: &[String], einfo: &mut RuntimeInfo, anonymizer: &Anonymizer) -> Vec::<String> {
+	if requested.is_empty() {
+		return alias_vec;
+	}
+	let mut selected = Vec::<String>::new();
+	for alias in requested {
+		if alias_vec.contains(alias) {
+			selected.push(alias.to_string());
 		}
-		false => {
-			match UserInput::alias_selector(alias_vec) {
-				Ok(user_aliases) => {
-					let alias_obj = UserAlias{ alias: user_aliases };
-					let alias_str = serde_json::to_string(&alias_obj).unwrap_or_default();
-					match writer.writeln(alias_str.as_str().as_ref()) {
-						Ok(_) => {},
-						Err(writer_err) => {
-							eprintln!("Unable to record user aliases in output file : {writer_err}");
-							einfo.record_err(writer_err.to_string().as_str().as_ref());
-							let _res = writer.finish(); // result doesn't matter since already in error
-							process::exit(1);
-						}
-					}
-				}
-				Err(error) => { 
-					eprintln!("Unable to process user aliases : {:?}", error);
-					einfo.record_err(error.to_string().as_str().as_ref());
-					let _res = writer.finish(); // result doesn't matter since already in error
-					process::exit(1); 
-				}
+		else {
+			eprintln!("Alias {alias} not found in any analyzed commit");
+			if let Some(recorded) = anonymizer.apply("aliases", alias) {
+				einfo.record_err(&format!("Alias {recorded} not found in any analyzed commit"));
 			}
 		}
-	}	
+	}
+	selected
 }
 
-fn main() {
-	let args = Cli::parse();
-	let mut dockermode = false;
-	match args.provider {
-		Some(ref argval) => {
-			if argval == "github" || argval == "bitbucket" {
-				dockermode = true;
+fn process_aliases(alias_vec: Vec::<String>, einfo: &mut RuntimeInfo, writer: &mut dyn OutputWriter, anonymizer: &Anonymizer) {
+	let alias_obj = UserAlias{ alias: alias_vec.iter().filter_map(|alias| anonymizer.apply("aliases", alias)).collect() };
+	match writer.write_record(&Record::Alias(&alias_obj)) {
+		Ok(_) => {},
+		Err(writer_err) => {
+			eprintln!("Unable to record user aliases in output file : {writer_err}");
+			einfo.record_err(writer_err.to_string().as_str());
+			let _res = writer.finish(); // result doesn't matter since already in error
+			process::exit(1);
+		}
+	}
+}
+
+fn finish_output(einfo: &mut RuntimeInfo, writer: &mut dyn OutputWriter, iowriter: &mut IoErrWriter, config: &Config) {
+	let _res = einfo.write_runtime_info(writer);
+	let _res = iowriter.finish();
+	match writer.finish() {
+		Ok(_) => {
+			let done_line = match config.get_bool("output.dry_run") {
+				true => "Dry run finished, nothing was written or uploaded",
+				false => "Extracted and uploaded metadata successfully! Proceed to https://vibinex.com/ to learn more",
+			};
+			match writer.is_stdout() {
+				true => eprintln!("{done_line}"),
+				false => println!("{done_line}"),
 			}
+		},
+		Err(error) => {
+			eprintln!("Unable to write to output : {error}");
+			process::exit(1);
 		}
-		None => {}
-	}
-	match OutputWriter::new() {
-		Ok(mut writer) => {
-			match dockermode {
-				true => {
-					let einfo = &mut RuntimeInfo::new();
-					unfinished_tasks(args.provider.as_ref().expect("Provider exists, checked"), args.repo_slug.as_ref().expect("No repo_slug"), einfo);
-					let writer_mut: &mut OutputWriter = &mut writer;
-					let einfo = &mut RuntimeInfo::new();
-					let scan_pathbuf = match args.path {
-						Some(scan_pathbuf) => scan_pathbuf,
-						None => Path::new("/").to_path_buf()
-					};
-					let rscanner = RepoScanner::new(scan_pathbuf);
-					let pathsvec = rscanner.scan(einfo, writer_mut, dockermode);
-					let alias_vec = process_repos(pathsvec, einfo, writer_mut, args.repo_slug, args.provider);
-					process_aliases(alias_vec, einfo, writer_mut, dockermode);
-					let _res = einfo.write_runtime_info(writer_mut);
-					match writer.finish() {
-						Ok(_) => {
-							println!("Extracted and uploaded metadata successfully! Proceed to https://vibinex.com/ to learn more");
-						},
-						Err(error) => {
-							eprintln!("Unable to write to output : {error}");
-						}
+	}
+}
+
+fn create_writer(config: &Config) -> Box<dyn OutputWriter> {
+	match writer::from_config(config) {
+		Ok(writer) => writer,
+		Err(error) => {
+			eprintln!("Unable to start application : {error}");
+			process::exit(1);
+		}
+	}
+}
+
+fn load_checkpoints(config: &Config) -> CheckpointStore {
+	match CheckpointStore::load(config) {
+		Ok(checkpoints) => checkpoints,
+		Err(error) => {
+			eprintln!("Unable to load checkpoints, rerun with --full or remove the state file : {error}");
+			process::exit(1);
+		}
+	}
+}
+
+fn save_checkpoints(checkpoints: &CheckpointStore, einfo: &mut RuntimeInfo, config: &Config) {
+	// a dry run writes nothing, so the next run must see the same commits again
+	if config.get_bool("output.dry_run") {
+		return;
+	}
+	if let Err(error) = checkpoints.save() {
+		eprintln!("Unable to save checkpoints to {} : {error}", checkpoints.path().display());
+		einfo.record_err(error.to_string().as_str());
+	}
+}
+
+fn load_options(config: &Config) -> AnalyzerOptions {
+	match AnalyzerOptions::from_config(config) {
+		Ok(options) => options,
+		Err(error) => {
+			eprintln!("Invalid analysis settings : {error}");
+			process::exit(1);
+		}
+	}
+}
+
+fn load_config() -> Config {
+	match Config::load() {
+		Ok(config) => config,
+		Err(error) => {
+			eprintln!("Unable to load configuration : {error}");
+			process::exit(1);
+		}
+	}
+}
+
+fn create_scanner(scan_path: PathBuf, config: &Config) -> RepoScanner {
+	match RepoScanner::new(scan_path, config) {
+		Ok(scanner) => scanner,
+		Err(error) => {
+			eprintln!("Invalid scan settings : {error}");
+			process::exit(1);
+		}
+	}
+}
+
+fn run_scan(config: &Config) {
+	let einfo = &mut RuntimeInfo::new();
+	let iowriter = &mut IoErrWriter::from_config(config);
+	let rscanner = create_scanner(PathBuf::from(config.get_str("scan.path")), config);
+	let pathsvec = rscanner.scan(einfo, iowriter, Progress::Hidden);
+	let _res = iowriter.finish();
+	for path in pathsvec {
+		println!("{path}");
+	}
+}
+
+fn run_analyze(args: AnalyzeArgs, config: &Config, repo_slug: Option<String>, provider: Option<String>) {
+	let options = load_options(config);
+	let mut output = create_writer(config);
+	let writer = output.as_mut();
+	let einfo = &mut RuntimeInfo::new();
+	let iowriter = &mut IoErrWriter::from_config(config);
+	let pathsvec = match args.repos.is_empty() {
+		true => create_scanner(PathBuf::from(config.get_str("scan.path")), config).scan(einfo, iowriter, Progress::Hidden),
+		false => args.repos,
+	};
+	let checkpoints = &mut load_checkpoints(config);
+	let alias_vec = process_repos(pathsvec, einfo, writer, checkpoints, repo_slug, provider, &options, Progress::Hidden);
+	let selected = select_aliases(alias_vec, &args.aliases, einfo, &options.anonymizer);
+	process_aliases(selected, einfo, writer, &options.anonymizer);
+	finish_output(einfo, writer, iowriter, config);
+	save_checkpoints(checkpoints, einfo, config);
+}
+
+fn run_review_hunks(args: &ReviewArgs, config: &Config) {
+	let einfo = &mut RuntimeInfo::new();
+	unfinished_tasks(&args.provider, &args.repo_slug, config, einfo);
+}
+
+fn run_report(args: ReportArgs, config: &Config) {
+	run_review_hunks(&args.review, config);
+	run_analyze(args.analyze, config, Some(args.review.repo_slug), Some(args.review.provider));
+}
+
+fn run_inspect(args: &InspectArgs) {
+	match inspect::inspect(&args.file) {
+		Ok(true) => {},
+		Ok(false) => process::exit(1),
+		Err(error) => {
+			eprintln!("Unable to inspect {} : {error}", args.file.display());
+			process::exit(1);
+		}
+	}
+}
+
+fn run_interactive(config: &Config) {
+	let options = load_options(config);
+	let mut output = create_writer(config);
+	let writer = output.as_mut();
+	match UserInput::scan_path() {
+		Ok(scan_path_str) => {
+			let einfo = &mut RuntimeInfo::new();
+			let iowriter = &mut IoErrWriter::from_config(config);
+			let scan_pathbuf = Path::new(&scan_path_str).to_path_buf();
+			let rscanner = create_scanner(scan_pathbuf, config);
+			let progress = Progress::shown(writer.is_stdout());
+			let pathsvec = rscanner.scan(einfo, iowriter, progress);
+			match UserInput::repo_selection(pathsvec) {
+				Ok(user_paths) => {
+					let checkpoints = &mut load_checkpoints(config);
+					let alias_vec = process_repos(user_paths, einfo, writer, checkpoints, None, None, &options, progress);
+					if alias_vec.is_empty() {
+						eprintln!("No aliases found in the analyzed repositories");
+						finish_output(einfo, writer, iowriter, config);
+						save_checkpoints(checkpoints, einfo, config);
+						return;
 					}
-				}
-				false => {
-					match UserInput::scan_path() {
-						Ok(scan_path_str) => {
-							let writer_mut: &mut OutputWriter = &mut writer;
-							let einfo = &mut RuntimeInfo::new();
-							let scan_pathbuf = Path::new(&scan_path_str).to_path_buf();
-							let rscanner = RepoScanner::new(scan_pathbuf);
-							let pathsvec = rscanner.scan(einfo, writer_mut, dockermode);
-							match UserInput::repo_selection(pathsvec) {
-								Ok(user_paths) => {
-									let alias_vec = process_repos(user_paths, einfo, writer_mut, None, None);
-									process_aliases(alias_vec, einfo, writer_mut, dockermode);
-									let _res = einfo.write_runtime_info(writer_mut);
-									match writer.finish() {
-										Ok(_) => {
-											println!("Extracted and uploaded metadata successfully! Proceed to https://vibinex.com/ to learn more");
-										},
-										Err(error) => {
-											eprintln!("Unable to write to output : {error}");
-										}
-									}
-								},
-								Err(error) => {
-									eprintln!("Unable to process repository selection : {error}");
-								}
-							} 
-						},
+					match UserInput::alias_selector(alias_vec) {
+						Ok(user_aliases) => {
+							process_aliases(user_aliases, einfo, writer, &options.anonymizer);
+							finish_output(einfo, writer, iowriter, config);
+							save_checkpoints(checkpoints, einfo, config);
+						}
 						Err(error) => {
-							eprintln!("Unable to write to present directory : {error}");
+							eprintln!("Unable to process user aliases : {:?}", error);
+							einfo.record_err(error.to_string().as_str());
+							let _res = writer.finish(); // result doesn't matter since already in error
+							process::exit(1);
 						}
 					}
+				},
+				Err(error) => {
+					eprintln!("Unable to process repository selection : {error}");
 				}
 			}
 		},
 		Err(error) => {
-			eprintln!("Unable to start application : {error}");
+			eprintln!("Unable to write to present directory : {error}");
 		}
 	}
 }
+
+fn main() {
+	let args = Cli::parse();
+	let mut config = load_config();
+	args.apply(&mut config);
+	match args.command {
+		Some(Commands::Scan(scan_args)) => {
+			scan_args.apply(&mut config);
+			run_scan(&config);
+		},
+		Some(Commands::Analyze(analyze_args)) => {
+			analyze_args.apply(&mut config);
+			run_analyze(analyze_args, &config, None, None);
+		},
+		Some(Commands::ReviewHunks(review_args)) => {
+			review_args.apply(&mut config);
+			run_review_hunks(&review_args, &config);
+		},
+		Some(Commands::Report(report_args)) => {
+			report_args.apply(&mut config);
+			run_report(report_args, &config);
+		},
+		Some(Commands::Config(ConfigCommands::Show)) => config.show(),
+		Some(Commands::Inspect(inspect_args)) => run_inspect(&inspect_args),
+		Some(Commands::Schema) => println!("{}",
+			serde_json::to_string_pretty(&writer::record_schema()).expect("Schema serializes to JSON")),
+		None => run_interactive(&config),
+	}
+}
 // git diff a9e58c7 8433a5e -U0
 // git blame a9e58c7 -L 121,+5 -e --date=unix devprofiler/src/main.rs
 // git diff a9e58c7:devprofiler/src/analyzer.rs 8433a5e:devprofiler/src/analyzer.rs'
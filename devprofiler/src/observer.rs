@@ -1,13 +1,47 @@
-use crate::writer::OutputWriter;
+use crate::writer::{OutputWriter, Record};
+use schemars::JsonSchema;
+use std::io::{self, Write};
 use serde::Serialize;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Default)]
+/// Where lines updated in place are printed. Stdout is left alone when it
+/// carries the profile archive, so that piping the archive stays safe.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Progress {
+    Hidden,
+    Stdout,
+    Stderr,
+}
+
+impl Progress {
+    pub fn shown(output_is_stdout: bool) -> Self {
+        match output_is_stdout {
+            true => Self::Stderr,
+            false => Self::Stdout,
+        }
+    }
+
+    pub fn print(self, line: &str) {
+        match self {
+            Self::Hidden => {},
+            Self::Stdout => {
+                print!("{line}\r");
+                let _res = io::stdout().flush();
+            },
+            Self::Stderr => {
+                eprint!("{line}\r");
+                let _res = io::stderr().flush();
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema, Default)]
 pub struct RuntimeInfo {
-    errors: Vec<String>,
-    version: String,
-    timestamp: u64,
-    logs: Vec<String>,
+    pub(crate) errors: Vec<String>,
+    pub(crate) version: String,
+    pub(crate) timestamp: u64,
+    pub(crate) logs: Vec<String>,
 }
 
 impl RuntimeInfo {
@@ -26,8 +60,12 @@ impl RuntimeInfo {
     pub fn record_err(&mut self, estr: &str) {
         self.errors.push(estr.to_string());
     }
-    pub fn write_runtime_info(&self, writer: &mut OutputWriter) -> Result<(), std::io::Error>{
-        let serialized = serde_json::to_string(&self).unwrap_or_default();
-        writer.writeln(serialized.as_str().as_ref())
+    /// Adds the errors and logs collected by an analysis worker.
+    pub fn merge(&mut self, other: RuntimeInfo) {
+        self.errors.extend(other.errors);
+        self.logs.extend(other.logs);
+    }
+    pub fn write_runtime_info(&self, writer: &mut dyn OutputWriter) -> Result<(), std::io::Error>{
+        writer.write_record(&Record::Runtime(self))
     }
 }
\ No newline at end of file
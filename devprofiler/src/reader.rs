@@ -18,7 +18,7 @@ impl UserInput {
             (format!("Select relevant repo(s) out of {} repo(s)", options.len())).as_str(), 
             options)
             .with_validator(|a: &[ListOption<&String>]| {
-                if a.len() < 1 {
+                if a.is_empty() {
                     return Ok(Validation::Invalid("Please select at least one repo".into()));
                 }
                 Ok(Validation::Valid)
@@ -31,7 +31,7 @@ impl UserInput {
             (format!("Select your email alias(es) out of {} alias(es)", options.len())).as_str(),
              options)
             .with_validator(|a: &[ListOption<&String>]| {
-                if a.len() < 1 {
+                if a.is_empty() {
                     return Ok(Validation::Invalid("Please select at least one alias".into()));
                 }
                 Ok(Validation::Valid)
@@ -3,8 +3,10 @@ use std::error::Error;
 use std::process::Command;
 use std::str;
 use std::collections::HashMap;
-use sha256::digest;
 use crate::observer::RuntimeInfo;
+use crate::config::Config;
+use crate::anonymizer::Anonymizer;
+use crate::writer;
 
 #[derive(Debug, Serialize, Default, Deserialize)]
 struct Reviews {
@@ -24,13 +26,16 @@ struct StatItem {
 	additions: i32,
 	deletions: i32,
 }
+/// Author and filepath are written per their privacy mode, omitted when dropped.
 #[derive(Debug, Serialize, Default, Deserialize)]
 struct BlameItem {
-	author: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	author: Option<String>,
 	timestamp: String,
 	line_start: String,
 	line_end: String,
-	filepath: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	filepath: Option<String>,
 }
 
 #[derive(Debug, Serialize, Default, Deserialize)]
@@ -39,19 +44,13 @@ struct LineItem {
 	timestamp: String,
 }
 
-#[derive(Debug, Serialize, Default, Deserialize)]
-struct HunkPRMap {
-	repo_provider: String,
-	repo_owner: String,
-	repo_name: String,
-	prvec: Vec<BlameItem>,
-}
-
 #[derive(Debug, Serialize, Default, Deserialize)]
 struct HunkMap {
 	repo_provider: String,
-	repo_owner: String,
-	repo_name: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	repo_owner: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	repo_name: Option<String>,
 	prhunkvec: Vec<PrHunkItem>,
 }
 
@@ -85,13 +84,12 @@ fn generate_diff(prev_commit: &str, curr_commit: &str, smallfiles: &Vec<StatItem
 			}
 		};
 	}
-	return diffmap;
+	diffmap
 }
 
-fn process_blamelines(blamelines: &Vec<&str>, linenum: usize) -> HashMap<usize, LineItem> {
+fn process_blamelines(blamelines: &[&str], linenum: usize) -> HashMap<usize, LineItem> {
 	let mut linemap = HashMap::<usize, LineItem>::new();
-	for lnum  in 0..blamelines.len() {
-		let ln = blamelines[lnum];
+	for (lnum, ln) in blamelines.iter().enumerate() {
 		let wordvec: Vec<&str> = ln.split(" ").collect();
 		let mut author = wordvec[1];
 		let mut timestamp = wordvec[2];
@@ -99,7 +97,7 @@ fn process_blamelines(blamelines: &Vec<&str>, linenum: usize) -> HashMap<usize,
 		// Check if the second value is an email address (enclosed in angle brackets)
 		if !author.starts_with('(') && !author.ends_with('>') {
 			// Shift the index to the next non-empty value
-			while idx < wordvec.len() && (wordvec[idx] == "" || !wordvec[idx].starts_with('(')){
+			while idx < wordvec.len() && (wordvec[idx].is_empty() || !wordvec[idx].starts_with('(')){
 				idx += 1;
 			}
 			if idx < wordvec.len() {
@@ -112,10 +110,10 @@ fn process_blamelines(blamelines: &Vec<&str>, linenum: usize) -> HashMap<usize,
 		let authorstr = author.replace("(", "")
 			.replace("<", "")
 			.replace(">", "");
-		if timestamp == "" || timestamp.starts_with('(') {
-			idx = idx + 1;
-			while idx < wordvec.len() && (wordvec[idx] == "" || wordvec[idx].starts_with('(')) {
-				idx = idx + 1;
+		if timestamp.is_empty() || timestamp.starts_with('(') {
+			idx += 1;
+			while idx < wordvec.len() && (wordvec[idx].is_empty() || wordvec[idx].starts_with('(')) {
+				idx += 1;
 			}
 			if idx < wordvec.len() {
 				timestamp = wordvec[idx];
@@ -126,10 +124,10 @@ fn process_blamelines(blamelines: &Vec<&str>, linenum: usize) -> HashMap<usize,
 			LineItem { author: authorstr.to_string(), timestamp: timestamp.to_string() }
 		);
 	}
-	return linemap;
+	linemap
 }
 
-fn generate_blame(commit: &str, linemap: &HashMap<String, Vec<String>>, einfo: &mut RuntimeInfo) ->  Vec<BlameItem>{
+fn generate_blame(commit: &str, linemap: &HashMap<String, Vec<String>>, anonymizer: &Anonymizer, einfo: &mut RuntimeInfo) ->  Vec<BlameItem>{
 	let mut blamevec = Vec::<BlameItem>::new();
 	for (path, linevec) in linemap {
 		for line in linevec {
@@ -149,7 +147,7 @@ fn generate_blame(commit: &str, linemap: &HashMap<String, Vec<String>>, einfo: &
 					match str::from_utf8(&blame) {
 						Ok(blamestr) => {
 							let blamelines: Vec<&str> = blamestr.lines().collect();
-							if blamelines.len() == 0 {
+							if blamelines.is_empty() {
 								continue;
 							}
 							let linenumint = linenum.parse::<usize>().expect("Unable to parse linenum");
@@ -164,11 +162,11 @@ fn generate_blame(commit: &str, linemap: &HashMap<String, Vec<String>>, einfo: &
 									}
 									else {
 										blamevec.push(BlameItem {
-											author: lineitem.author.to_string(),
+											author: anonymizer.apply("blame_author", &lineitem.author),
 											timestamp: lineitem.timestamp.to_string(),
 											line_start: linebreak.to_string(),
 											line_end: lidx.to_string(),
-											filepath: digest(path.as_str()) });
+											filepath: anonymizer.apply("path", path) });
 										linebreak = lidx + 1;
 									}
 								}
@@ -177,11 +175,11 @@ fn generate_blame(commit: &str, linemap: &HashMap<String, Vec<String>>, einfo: &
 							if lineauthormap.contains_key(&lastidx) {
 								let lineitem = lineauthormap.get(&lastidx).expect("lastidx checked");
 								blamevec.push(BlameItem {
-									author: lineitem.author.to_string(),
+									author: anonymizer.apply("blame_author", &lineitem.author),
 									timestamp: lineitem.timestamp.to_string(),
 									line_start: linebreak.to_string(),
 									line_end: lastidx.to_string(),
-									filepath: digest(path.as_str()) });
+									filepath: anonymizer.apply("path", path) });
 
 							}
 							
@@ -196,7 +194,7 @@ fn generate_blame(commit: &str, linemap: &HashMap<String, Vec<String>>, einfo: &
 			}
 		}
 	}
-	return blamevec;
+	blamevec
 }
 
 fn process_reposlug(repo_slug: &str) -> (String, String) {
@@ -211,11 +209,10 @@ fn process_reposlug(repo_slug: &str) -> (String, String) {
 		repo_name = repo_slug;
 		repo_owner = "";
 	}
-	return (repo_name.to_string(), repo_owner.to_string());
+	(repo_name.to_string(), repo_owner.to_string())
 }
 
-fn get_tasks(provider: &str, repo_slug: &str, einfo: &mut RuntimeInfo) -> Option<Reviews>{
-	let api_url = "https://gcscruncsql-k7jns52mtq-el.a.run.app/relevance/hunk";
+fn get_tasks(provider: &str, repo_slug: &str, api_url: &str, einfo: &mut RuntimeInfo) -> Option<Reviews>{
 	let client = reqwest::blocking::Client::new();
 	let mut map = HashMap::new();
 	let (repo_name, repo_owner) = process_reposlug(repo_slug);
@@ -240,18 +237,26 @@ fn get_tasks(provider: &str, repo_slug: &str, einfo: &mut RuntimeInfo) -> Option
 	};
 	// .expect("Get request failed")
     //     .json::<Reviews>().expect("Json parsing of response failed");
-	return response;
+	response
+}
+
+/// Progress goes to stderr when the profile archive is written to stdout,
+/// so that piping the archive stays safe.
+fn print_progress(config: &Config, line: &str) {
+	match writer::output_path(config) {
+		Some(_) => println!("{line}"),
+		None => eprintln!("{line}"),
+	}
 }
 
-fn store_hunkmap(hunkmap: HunkMap, einfo: &mut RuntimeInfo) {
-	let api_url = "https://gcscruncsql-k7jns52mtq-el.a.run.app/relevance/hunk/store";
+fn store_hunkmap(hunkmap: HunkMap, config: &Config, einfo: &mut RuntimeInfo) {
 	let client = reqwest::blocking::Client::new();
-	match client.post(api_url).json(&hunkmap).send(){
+	match client.post(config.get_str("api.store_url")).json(&hunkmap).send(){
 		Ok(response) => {
 			match response.text() {
 				Ok(restext) => {
 					einfo.record_err(&restext);
-					println!("Hunk relevance task complete");
+					print_progress(config, "Hunk relevance task complete");
 				},
 				Err(reserr) => {einfo.record_err(reserr.to_string().as_str());}
 			}
@@ -261,11 +266,11 @@ fn store_hunkmap(hunkmap: HunkMap, einfo: &mut RuntimeInfo) {
 	}
 }
 
-fn get_excluded_files(prev_commit: &str, next_commit: &str, einfo: &mut RuntimeInfo) -> Option<(Vec<StatItem>, Vec<StatItem>)> {
+fn get_excluded_files(prev_commit: &str, next_commit: &str, line_threshold: i32, einfo: &mut RuntimeInfo) -> Option<(Vec<StatItem>, Vec<StatItem>)> {
 	// Use the command
 	let commit_range = format!("{}...{}", prev_commit, next_commit);
 	match Command::new("git")
-		.args(&["diff", &commit_range, "--numstat"])
+		.args(["diff", &commit_range, "--numstat"])
 		.output() {
 			Ok(resultstat) => {
 				let stat = resultstat.stdout;
@@ -279,14 +284,8 @@ fn get_excluded_files(prev_commit: &str, next_commit: &str, einfo: &mut RuntimeI
 							if statitems.len() >= 3 {
 								let statitem = StatItem {
 									filepath: statitems[2].to_string(),
-									additions: match statitems[0].to_string().parse() {
-										Ok(adds) => {adds}
-										Err(e) => {0}
-									},
-									deletions: match statitems[0].to_string().parse() {
-										Ok(dels) => {dels}
-										Err(e) => {0}
-									},
+									additions: statitems[0].to_string().parse().unwrap_or_default(),
+									deletions: statitems[0].to_string().parse().unwrap_or_default(),
 								};
 								statvec.push(statitem);
 							}
@@ -294,7 +293,6 @@ fn get_excluded_files(prev_commit: &str, next_commit: &str, einfo: &mut RuntimeI
 						// logic for exclusion
 						let mut bigfiles = Vec::<StatItem>::new();
 						let mut smallfiles = Vec::<StatItem>::new();
-						let line_threshold = 500;
 						for item in statvec {
 							if (item.additions > line_threshold) || 
 							(item.deletions > line_threshold) || 
@@ -317,7 +315,7 @@ fn get_excluded_files(prev_commit: &str, next_commit: &str, einfo: &mut RuntimeI
 		}
 	
 	// compile the result and return
-	return None;
+	None
 }
 
 fn process_diff(diffmap: &HashMap<String, String>) -> Result<HashMap<String, Vec<String>>,Box<dyn Error>> {
@@ -367,26 +365,34 @@ fn process_diff(diffmap: &HashMap<String, String>) -> Result<HashMap<String, Vec
 			idx += 1;
 		}
 	}
-	return Ok(linemap);
+	Ok(linemap)
 }
 
-pub(crate) fn unfinished_tasks(provider: &str, repo_slug: &str, einfo: &mut RuntimeInfo) {
-	let reviews = get_tasks(provider, repo_slug, einfo);
-	if reviews.is_some() {
+pub(crate) fn unfinished_tasks(provider: &str, repo_slug: &str, config: &Config, einfo: &mut RuntimeInfo) {
+	let line_threshold = i32::try_from(config.get_int("review.line_threshold")).unwrap_or(i32::MAX);
+	let anonymizer = match Anonymizer::from_config(config) {
+		Ok(anonymizer) => anonymizer,
+		Err(error) => {
+			eprintln!("Invalid privacy settings : {error}");
+			einfo.record_err(error.to_string().as_str());
+			return;
+		}
+	};
+	let reviews = get_tasks(provider, repo_slug, &config.get_str("api.tasks_url"), einfo);
+	if let Some(reviews) = reviews {
 		let mut prvec = Vec::<PrHunkItem>::new();
-		for review in reviews.expect("Validated reviews").reviews {
-			println!("Processing PR : {}", review.id);
-			let fileopt = get_excluded_files(&review.base_head_commit, &review.pr_head_commit, einfo);
-			if fileopt.is_some() {
-				let (bigfiles, smallfiles) = fileopt.expect("Validated fileopt");
+		for review in reviews.reviews {
+			print_progress(config, &format!("Processing PR : {}", review.id));
+			let fileopt = get_excluded_files(&review.base_head_commit, &review.pr_head_commit, line_threshold, einfo);
+			if let Some((_bigfiles, smallfiles)) = fileopt {
 				let diffmap = generate_diff(&review.base_head_commit, &review.pr_head_commit, &smallfiles, einfo);
 				let diffres = process_diff(&diffmap);
 				match diffres {
 					Ok(linemap) => {
-						let blamevec = generate_blame(&review.base_head_commit, &linemap, einfo);
+						let blamevec = generate_blame(&review.base_head_commit, &linemap, &anonymizer, einfo);
 						let hmapitem = PrHunkItem {
 							pr_number: review.id,
-							blamevec: blamevec,
+							blamevec,
 						};
 						prvec.push(hmapitem);
 					}
@@ -399,7 +405,13 @@ pub(crate) fn unfinished_tasks(provider: &str, repo_slug: &str, einfo: &mut Runt
 		}
 		let (repo_name, repo_owner) = process_reposlug(repo_slug);
 		let hunkmap = HunkMap { repo_provider: provider.to_string(),
-			repo_owner: repo_owner, repo_name: repo_name, prhunkvec: prvec };
-		store_hunkmap(hunkmap, einfo);
+			repo_owner: anonymizer.apply("repo_name", &repo_owner),
+			repo_name: anonymizer.apply("repo_name", &repo_name),
+			prhunkvec: prvec };
+		match config.get_bool("output.dry_run") {
+			true => print_progress(config, &format!("Dry run, would upload to {} :\n{}", config.get_str("api.store_url"),
+				serde_json::to_string_pretty(&hunkmap).unwrap_or_default())),
+			false => store_hunkmap(hunkmap, config, einfo),
+		}
 	}
 }
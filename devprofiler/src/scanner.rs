@@ -1,71 +1,367 @@
-use std::path::PathBuf;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
-use crate::observer::RuntimeInfo;
-use crate::writer::OutputWriter;
-use std::io;
-use std::io::Write;
+use crate::config::Config;
+use crate::exclude::anchor_pattern;
+use crate::observer::{Progress, RuntimeInfo};
+use crate::writer::IoErrWriter;
 
 pub struct RepoScanner {
-    scanpath: PathBuf
+    scanpath: PathBuf,
+    /// Directory names never descended into, e.g. node_modules
+    prune: Vec<String>,
+    /// 0 walks the whole tree
+    max_depth: usize,
+    /// Only repositories matching these are reported, when there are any
+    include: Option<ScanRules>,
+    exclude: ScanRules,
+    /// Stay on the filesystem of the scan path
+    one_file_system: bool,
+    follow_symlinks: bool,
+}
+
+/// Kernel and device filesystems skipped when scanning from the root.
+const PSEUDO_FILESYSTEMS: [&str; 4] = ["/proc", "/sys", "/dev", "/run"];
+
+/// Per-directory ignore file, holding globs relative to its directory.
+const IGNORE_FILE: &str = ".devprofilerignore";
+
+/// Globs along with the rule each came from, so that skipped directories
+/// can be counted per rule.
+struct ScanRules {
+    globs: GlobSet,
+    rules: Vec<String>,
+}
+
+impl ScanRules {
+    /// Builds globs anchored like `.gitignore` patterns: without an inner
+    /// slash they match at any depth below `base`, otherwise from `base`.
+    /// `~/` and a leading slash given in the configuration start at the home
+    /// and root directories instead.
+    fn build(patterns: &[String], base: &Path, source: &str, from_config: bool) -> Result<Self, String> {
+        let mut builder = GlobSetBuilder::new();
+        let mut rules = Vec::<String>::new();
+        // joined with a slash below, which the root and paths given with a
+        // trailing slash already end in
+        let base = globset::escape(base.to_string_lossy().trim_end_matches('/'));
+        for pattern in patterns {
+            let trimmed = pattern.trim_end_matches('/');
+            let home = env::var_os("HOME").map(|home| globset::escape(home.to_string_lossy().trim_end_matches('/')));
+            let anchored = match (trimmed.strip_prefix("~/"), home) {
+                (Some(in_home), Some(home)) if from_config => format!("{home}/{in_home}"),
+                _ if from_config && trimmed.starts_with('/') => trimmed.to_string(),
+                _ => format!("{base}/{}", anchor_pattern(trimmed)),
+            };
+            let glob = GlobBuilder::new(&anchored).literal_separator(true).build()
+                .map_err(|err| format!("Invalid pattern {pattern} in {source} : {err}"))?;
+            builder.add(glob);
+            rules.push(format!("{source}: {pattern}"));
+        }
+        Ok(Self {
+            globs: builder.build().map_err(|err| format!("Invalid patterns in {source} : {err}"))?,
+            rules,
+        })
+    }
+
+    fn first_match(&self, path: &Path) -> Option<&str> {
+        self.globs.matches(path).first().map(|idx| self.rules[*idx].as_str())
+    }
+
+    /// Whether the path or one of its ancestors matches.
+    fn covers(&self, path: &Path) -> bool {
+        path.ancestors().any(|ancestor| self.globs.is_match(ancestor))
+    }
+}
+
+/// A repository found while scanning, keyed by its common git directory so
+/// that worktrees of one repository are reported once.
+struct FoundRepo {
+    path: PathBuf,
+    common_dir: PathBuf,
+    /// Working tree holding the `.git` directory itself
+    is_main: bool,
+    is_bare: bool,
 }
 
 impl RepoScanner {
-    pub fn new(scanpath: PathBuf) -> Self{
-        Self { scanpath }
+    pub fn new(scanpath: PathBuf, config: &Config) -> Result<Self, String> {
+        // globs from the configuration may be absolute, so walked paths have to be too
+        let scanpath = std::path::absolute(&scanpath)
+            .map_err(|err| format!("Unable to resolve scan path {} : {err}", scanpath.display()))?;
+        let include = config.get_list("scan.include");
+        let include = match include.is_empty() {
+            true => None,
+            false => Some(ScanRules::build(&include, &scanpath, "scan.include", true)?),
+        };
+        let exclude = ScanRules::build(&config.get_list("scan.exclude"), &scanpath, "scan.exclude", true)?;
+        Ok(Self {
+            scanpath,
+            prune: config.get_list("scan.prune"),
+            max_depth: config.get_int("scan.max_depth").max(0) as usize,
+            include,
+            exclude,
+            one_file_system: config.get_bool("scan.one_file_system"),
+            follow_symlinks: config.get_bool("scan.follow_symlinks"),
+        })
     }
 
-    pub fn scan(&self, einfo: &mut RuntimeInfo, writer: &mut OutputWriter, dockermode: bool) -> Vec<String>{
-        let walker = WalkDir::new(self.scanpath.as_path()).into_iter();
-        let mut repo_paths = Vec::<String>::new();
+    pub fn scan(&self, einfo: &mut RuntimeInfo, iowriter: &mut IoErrWriter, progress: Progress) -> Vec<String>{
+        let mut walkdir = WalkDir::new(self.scanpath.as_path())
+            .sort_by_file_name()
+            .same_file_system(self.one_file_system)
+            .follow_links(self.follow_symlinks);
+        if self.max_depth > 0 {
+            walkdir = walkdir.max_depth(self.max_depth);
+        }
+        let mut walker = walkdir.into_iter();
+        let mut repos = Vec::<FoundRepo>::new();
+        let mut seen = HashMap::<PathBuf, usize>::new();
+        // ignore files of the directories above the current entry, by depth
+        let mut ignores = Vec::<(usize, ScanRules)>::new();
+        let mut skipped = BTreeMap::<String, usize>::new();
+        let mut skipped_repos = 0;
+        // directories reachable through several symlinks are walked once
+        let mut visited = HashSet::<PathBuf>::new();
+        let from_root = Self::canonical(&self.scanpath) == Path::new("/");
         let mut scan_err = false;
+        let mut log_err = false;
         let mut count = 0;
-        for entry in walker.filter_map(|elem| {
-            if elem.is_err() {
-                let err_str = elem.err().expect("Checked, is err")
-                    .to_string();
-                einfo.record_err(&err_str);
-                match writer.write_io_err(&err_str) {
-                    Ok(_) => {},
-                    Err(error) => { 
-                        scan_err = true;
-                        einfo.record_err(
-                            error.to_string().as_str().as_ref());
+        while let Some(elem) = walker.next() {
+            let entry = match elem {
+                Ok(entry) => entry,
+                Err(walk_err) if walk_err.loop_ancestor().is_some() => {
+                    // walkdir refuses to descend into a symlink to one of its ancestors
+                    *skipped.entry("symlink cycle".to_string()).or_default() += 1;
+                    continue;
+                },
+                Err(walk_err) => {
+                    let err_str = walk_err.to_string();
+                    einfo.record_err(&err_str);
+                    match iowriter.write_io_err(&err_str) {
+                        Ok(_) => { scan_err = true; },
+                        Err(error) => {
+                            if !log_err {
+                                eprintln!("Unable to record I/O errors : {error}");
+                            }
+                            log_err = true;
+                            einfo.record_err(
+                                error.to_string().as_str());
+                        }
                     }
+                    continue;
                 }
-                None
-            }
-            else{
-                elem.ok()
+            };
+            if !entry.file_type().is_dir() {
+                continue;
             }
-        }) 
-        {
             count += 1;
-            if !dockermode {
-                Self::print_progress(count);
+            Self::print_progress(progress, count);
+            let name = entry.file_name().to_string_lossy();
+            // git internals hold no repositories worth reporting
+            if entry.depth() > 0 && (name == ".git" || self.prune.iter().any(|pruned| *pruned == name)) {
+                walker.skip_current_dir();
+                continue;
             }
             let path = entry.path();
-            if path.ends_with(".git") {
-                repo_paths.push(
-                    path.parent()
-                    .expect("None only when path = /")
-                    .to_str().expect("None only when path = /")
-                    .to_string());
+            ignores.retain(|(depth, _)| *depth < entry.depth());
+            let skip_rule = match entry.depth() {
+                0 => None,
+                _ => self.exclude.first_match(path)
+                    .or_else(|| ignores.iter().find_map(|(_, rules)| rules.first_match(path))),
+            };
+            let skip_rule = skip_rule.or_else(|| {
+                let is_pseudo = from_root && entry.depth() == 1
+                    && PSEUDO_FILESYSTEMS.iter().any(|pseudo| Self::canonical(path) == Path::new(pseudo));
+                is_pseudo.then_some("pseudo filesystem")
+            }).or_else(|| {
+                let is_revisit = self.follow_symlinks && !visited.insert(Self::canonical(path));
+                is_revisit.then_some("directory already scanned through a symlink")
+            });
+            if let Some(rule) = skip_rule {
+                *skipped.entry(rule.to_string()).or_default() += 1;
+                walker.skip_current_dir();
+                continue;
+            }
+            if let Some(rules) = Self::read_ignore_file(path) {
+                ignores.push((entry.depth(), rules));
+            }
+            let found = match Self::find_repo(path) {
+                Some(found) => found,
+                None => continue,
+            };
+            if found.is_bare {
+                // a bare repository has no working tree to look into
+                walker.skip_current_dir();
+            }
+            if self.include.as_ref().is_some_and(|include| !include.covers(&found.path)) {
+                skipped_repos += 1;
+                continue;
+            }
+            match seen.get(&found.common_dir) {
+                // another worktree of a repository already found, the main one wins
+                Some(&idx) => if found.is_main && !repos[idx].is_main {
+                    repos[idx] = found;
+                },
+                None => {
+                    seen.insert(found.common_dir.clone(), repos.len());
+                    repos.push(found);
+                }
             }
         }
         if scan_err {
-            eprintln!("Some directories were inaccessible. I/O errors are detailed in io_errors.txt");
+            eprintln!("Some directories were inaccessible. I/O errors are detailed in {}", iowriter.path().display());
+        }
+        // rules name paths, so the counts stay out of the runtime record
+        for (rule, count) in skipped {
+            eprintln!("Skipped {count} directories ({rule})");
+        }
+        if skipped_repos > 0 {
+            eprintln!("Skipped {skipped_repos} repositories (scan.include)");
+        }
+        repos.into_iter()
+            .map(|found| found.path.to_string_lossy().to_string())
+            .collect()
+    }
+
+    /// Errors are only printed, as the runtime record is written out and the
+    /// paths they name are not covered by the privacy settings.
+    fn read_ignore_file(dir: &Path) -> Option<ScanRules> {
+        let ignore_path = dir.join(IGNORE_FILE);
+        let contents = fs::read_to_string(&ignore_path).ok()?;
+        // negated patterns are not supported, so they are left out
+        let patterns: Vec<String> = contents.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('!'))
+            .map(str::to_string)
+            .collect();
+        match ScanRules::build(&patterns, dir, &ignore_path.to_string_lossy(), false) {
+            Ok(rules) => Some(rules),
+            Err(error) => {
+                eprintln!("{error}");
+                None
+            }
+        }
+    }
+
+    /// Recognizes a working tree with a `.git` directory, a worktree or
+    /// submodule whose `.git` file points elsewhere with `gitdir:`, or a bare
+    /// repository.
+    fn find_repo(path: &Path) -> Option<FoundRepo> {
+        let dot_git = path.join(".git");
+        if dot_git.is_dir() {
+            return Some(FoundRepo {
+                path: path.to_path_buf(),
+                common_dir: Self::canonical(&dot_git),
+                is_main: true,
+                is_bare: false,
+            });
+        }
+        if dot_git.is_file() {
+            let contents = fs::read_to_string(&dot_git).ok()?;
+            let git_dir = path.join(contents.trim().strip_prefix("gitdir:")?.trim());
+            // linked worktrees name the repository they belong to in commondir
+            let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+                Ok(common) => git_dir.join(common.trim()),
+                Err(_) => git_dir,
+            };
+            return Some(FoundRepo {
+                path: path.to_path_buf(),
+                common_dir: Self::canonical(&common_dir),
+                is_main: false,
+                is_bare: false,
+            });
         }
-        repo_paths
+        let is_bare = path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir();
+        is_bare.then(|| FoundRepo {
+            path: path.to_path_buf(),
+            common_dir: Self::canonical(path),
+            is_main: true,
+            is_bare: true,
+        })
     }
 
-    fn print_progress(count: i64) {
+    fn canonical(path: &Path) -> PathBuf {
+        fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+    }
+
+    fn print_progress(progress: Progress, count: i64) {
         let mut v = vec!["Scanning directories "];
             if count % 4 == 0 {v.push("/");}
             if count % 4 == 1 {v.push("-");}
             if count % 4 == 2 {v.push("\\");}
             if count % 4 == 3 {v.push("-");}
-            v.push(" \r");
-            print!("{}", v.concat());
-		    let _res = io::stdout().flush();
+            v.push(" ");
+            progress.print(&v.concat());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(patterns: &[&str], base: &str, from_config: bool) -> ScanRules {
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        ScanRules::build(&patterns, Path::new(base), "test", from_config).unwrap()
     }
-}
\ No newline at end of file
+
+    fn matches(rules: &ScanRules, path: &str) -> bool {
+        rules.first_match(Path::new(path)).is_some()
+    }
+
+    #[test]
+    fn anchors_patterns_at_the_root() {
+        let root = rules(&["node_modules", "/opt", "src/vendor"], "/", false);
+        assert!(matches(&root, "/node_modules"));
+        assert!(matches(&root, "/home/me/app/node_modules"));
+        assert!(matches(&root, "/opt"));
+        assert!(!matches(&root, "/home/opt"));
+        assert!(matches(&root, "/src/vendor"));
+        assert!(!matches(&root, "/home/src/vendor"));
+    }
+
+    #[test]
+    fn ignores_a_trailing_slash_of_the_base() {
+        let base = rules(&["build", "a/b"], "/home/me/src/", false);
+        assert!(matches(&base, "/home/me/src/build"));
+        assert!(matches(&base, "/home/me/src/app/build"));
+        assert!(!matches(&base, "/home/me/build"));
+        assert!(matches(&base, "/home/me/src/a/b"));
+    }
+
+    #[test]
+    fn anchors_patterns_with_an_inner_slash_at_the_base() {
+        let base = rules(&["a/b", "c/*/d/"], "/src", false);
+        assert!(matches(&base, "/src/a/b"));
+        assert!(!matches(&base, "/src/x/a/b"));
+        assert!(matches(&base, "/src/c/any/d"));
+        assert!(!matches(&base, "/src/c/x/y/d"));
+    }
+
+    #[test]
+    fn anchors_leading_slashes() {
+        // in the configuration a leading slash starts at the root
+        let config = rules(&["/opt/archive"], "/src", true);
+        assert!(matches(&config, "/opt/archive"));
+        assert!(!matches(&config, "/src/opt/archive"));
+        // in an ignore file it starts at the directory of the file
+        let ignore = rules(&["/build"], "/src", false);
+        assert!(matches(&ignore, "/src/build"));
+        assert!(!matches(&ignore, "/src/app/build"));
+        assert!(!matches(&ignore, "/build"));
+    }
+
+    #[test]
+    fn anchors_home_patterns_in_the_configuration() {
+        let home = env::var("HOME").expect("HOME is set");
+        let home = home.trim_end_matches('/');
+        let config = rules(&["~/Library"], "/", true);
+        assert!(matches(&config, &format!("{home}/Library")));
+        assert!(!matches(&config, &format!("{home}/src/Library")));
+        // ignore files have no notion of home
+        let ignore = rules(&["~/Library"], "/src", false);
+        assert!(matches(&ignore, "/src/~/Library"));
+    }
+}
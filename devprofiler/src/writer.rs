@@ -1,28 +1,577 @@
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Error;
+use std::io::ErrorKind;
 use std::io::Write;
 use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::SyncSender;
+use chrono::Utc;
 use flate2::Compression;
 use flate2::write::GzEncoder;
+use rusqlite::{params, Connection};
+use schemars::JsonSchema;
+use schemars::schema::RootSchema;
+use serde::Serialize;
+use crate::analyzer::{CommitInfo, UserAlias};
+use crate::anonymizer::Anonymizer;
+use crate::config::Config;
+use crate::observer::RuntimeInfo;
 
-pub struct OutputWriter {
-    writer: GzEncoder<BufWriter<File>>,
-    iowriter: Option<BufWriter<File>>
+const STDOUT_PATH: &str = "-";
+
+/// Version of the record layout, bumped whenever a field is removed, renamed
+/// or changes meaning. Added optional fields keep the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// First record of every output, identifying its layout.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SchemaHeader {
+    pub(crate) schema_version: u32,
+    /// Version of devprofiler that wrote the output
+    pub(crate) generator: String,
+}
+
+impl SchemaHeader {
+    pub fn current() -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            generator: option_env!("CARGO_PKG_VERSION").unwrap_or("unknown").to_string(),
+        }
+    }
+}
+
+/// A single line of the profile, serialized as-is by the JSONL sinks with
+/// its kind in the `type` field.
+#[derive(Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record<'a> {
+    Header(&'a SchemaHeader),
+    Commit(&'a CommitInfo),
+    Alias(&'a UserAlias),
+    Runtime(&'a RuntimeInfo),
+}
+
+/// JSON Schema of a single record, generated from the structs above.
+pub fn record_schema() -> RootSchema {
+    schemars::schema_for!(Record<'static>)
+}
+
+pub trait OutputWriter {
+    fn write_record(&mut self, record: &Record) -> Result<(), Error>;
+    fn finish(&mut self) -> Result<(), Error>;
+    fn is_stdout(&self) -> bool;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    JsonlGz,
+    Ndjson,
+    Csv,
+    Sqlite,
+}
+
+impl OutputFormat {
+    pub const NAMES: [&'static str; 4] = ["jsonl-gz", "ndjson", "csv", "sqlite"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "jsonl-gz" => Some(Self::JsonlGz),
+            "ndjson" => Some(Self::Ndjson),
+            "csv" => Some(Self::Csv),
+            "sqlite" => Some(Self::Sqlite),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::JsonlGz => "jsonl.gz",
+            Self::Ndjson => "jsonl",
+            Self::Csv => "csv",
+            Self::Sqlite => "sqlite",
+        }
+    }
+}
+
+fn output_format(config: &Config) -> Result<OutputFormat, Error> {
+    let name = config.get_str("output.format");
+    OutputFormat::from_name(&name).ok_or_else(|| Error::new(ErrorKind::InvalidInput,
+        format!("Unknown output format {name}, expected one of {}", OutputFormat::NAMES.join(", "))))
+}
+
+/// Creates the sink selected by `output.format`, with the schema header
+/// already written.
+pub fn from_config(config: &Config) -> Result<Box<dyn OutputWriter>, Error> {
+    let mut writer = create_sink(config)?;
+    writer.write_record(&Record::Header(&SchemaHeader::current()))?;
+    Ok(writer)
+}
+
+fn create_sink(config: &Config) -> Result<Box<dyn OutputWriter>, Error> {
+    let format = output_format(config)?;
+    let path = output_path(config);
+    if config.get_bool("output.dry_run") {
+        return Ok(Box::new(DryRunWriter::new(config, path.as_deref())?));
+    }
+    let no_clobber = config.get_bool("output.no_clobber");
+    if format == OutputFormat::Sqlite {
+        let db_path = path.ok_or_else(|| Error::new(ErrorKind::InvalidInput,
+            "The sqlite format cannot be written to stdout"))?;
+        return Ok(Box::new(SqliteWriter::new(&db_path, no_clobber)?));
+    }
+    let to_stdout = path.is_none();
+    let sink: Box<dyn Write> = match path {
+        Some(path) => Box::new(create_file(&path, no_clobber)?),
+        None => Box::new(io::stdout()),
+    };
+    let bufw = BufWriter::new(sink);
+    Ok(match format {
+        OutputFormat::JsonlGz => Box::new(JsonlWriter {
+            sink: JsonlSink::Gz(GzEncoder::new(bufw, Compression::default())),
+            to_stdout,
+        }),
+        OutputFormat::Ndjson => Box::new(JsonlWriter { sink: JsonlSink::Plain(bufw), to_stdout }),
+        _ => Box::new(CsvWriter { writer: csv::Writer::from_writer(bufw), to_stdout }),
+    })
+}
+
+/// Resolves the output path from `output.path`, `output.format`,
+/// `output.dir` and `output.timestamp`. None means the output goes to stdout.
+pub fn output_path(config: &Config) -> Option<PathBuf> {
+    let path_str = config.get_str("output.path");
+    if path_str == STDOUT_PATH {
+        return None;
+    }
+    let mut path = match path_str.is_empty() {
+        true => {
+            let ext = output_format(config).map(|format| format.extension())
+                .unwrap_or(OutputFormat::JsonlGz.extension());
+            PathBuf::from(format!("devprofile.{ext}"))
+        },
+        false => PathBuf::from(path_str),
+    };
+    let dir = config.get_str("output.dir");
+    if !dir.is_empty() {
+        path = Path::new(&dir).join(path);
+    }
+    if config.get_bool("output.timestamp") {
+        add_timestamp(&mut path);
+    }
+    Some(path)
+}
+
+/// Inserts the current UTC time before the first extension of the filename.
+fn add_timestamp(path: &mut PathBuf) {
+    let filename = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    let stamped = match filename.split_once('.') {
+        Some((name, ext)) => format!("{name}-{timestamp}.{ext}"),
+        None => format!("{filename}-{timestamp}"),
+    };
+    path.set_file_name(stamped);
+}
+
+/// Directory receiving auxiliary files such as io_errors.txt.
+pub fn output_dir(config: &Config) -> PathBuf {
+    let dir = config.get_str("output.dir");
+    let parent = output_path(config)
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .filter(|parent| !parent.as_os_str().is_empty());
+    match parent {
+        Some(parent) => parent,
+        None if !dir.is_empty() => PathBuf::from(dir),
+        None => PathBuf::from("."),
+    }
+}
+
+fn create_file(path: &Path, no_clobber: bool) -> Result<File, Error> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    match no_clobber {
+        true => File::options().write(true).create_new(true).open(path)
+            .map_err(|err| match err.kind() {
+                ErrorKind::AlreadyExists => Error::new(ErrorKind::AlreadyExists,
+                    format!("{} already exists, refusing to overwrite", path.display())),
+                _ => err,
+            }),
+        false => File::create(path),
+    }
+}
+
+/// Writes nothing, and on finish prints the mode of every privacy field
+/// and the first record of each kind as it would have been written.
+struct DryRunWriter {
+    destination: String,
+    level: String,
+    modes: Vec<(&'static str, &'static str)>,
+    samples: Vec<(&'static str, String)>,
+    commits: usize,
 }
 
-impl OutputWriter {
-    pub fn new() -> Result<OutputWriter, Error>{
-        let file = File::create("devprofile.jsonl.gz")?;
-        let bufw = BufWriter::new(file);
-        let gze = GzEncoder::new(bufw, Compression::default());
-        Ok(Self{
-            writer: gze,
-            iowriter: None,
+impl DryRunWriter {
+    fn new(config: &Config, path: Option<&Path>) -> Result<Self, Error> {
+        let modes = Anonymizer::field_modes(config)
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        Ok(Self {
+            destination: match path {
+                Some(path) => path.display().to_string(),
+                None => "stdout".to_string(),
+            },
+            level: config.get_str("privacy.level"),
+            modes: modes.into_iter().map(|(field, mode)| (field, mode.name())).collect(),
+            samples: Vec::new(),
+            commits: 0,
         })
     }
+}
+
+impl OutputWriter for DryRunWriter {
+    fn write_record(&mut self, record: &Record) -> Result<(), Error> {
+        let kind = match record {
+            Record::Commit(_) => {
+                self.commits += 1;
+                "commit"
+            },
+            Record::Header(_) => "header",
+            Record::Alias(_) => "alias",
+            Record::Runtime(_) => "runtime",
+        };
+        if !self.samples.iter().any(|(seen, _)| *seen == kind) {
+            self.samples.push((kind, serde_json::to_string_pretty(record).unwrap_or_default()));
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        println!("Dry run, nothing was written to {}", self.destination);
+        println!("Privacy level {}, fields:", self.level);
+        for (field, mode) in &self.modes {
+            println!("  {field:<16} {mode}");
+        }
+        for (kind, sample) in &self.samples {
+            println!("Sample {kind} record:\n{sample}");
+        }
+        println!("{} commit records would be written", self.commits);
+        Ok(())
+    }
+
+    fn is_stdout(&self) -> bool {
+        true
+    }
+}
+
+/// Hands the commits of an analysis worker to the thread owning the output,
+/// so a single writer serializes every record. Each commit is sent along
+/// with the index of the repository it belongs to.
+pub struct ChannelWriter<T> {
+    sender: SyncSender<T>,
+    repo_idx: usize,
+}
+
+impl<T> ChannelWriter<T> {
+    pub fn new(sender: SyncSender<T>, repo_idx: usize) -> Self {
+        Self { sender, repo_idx }
+    }
+}
+
+impl<T: From<(usize, CommitInfo)>> OutputWriter for ChannelWriter<T> {
+    fn write_record(&mut self, record: &Record) -> Result<(), Error> {
+        match record {
+            Record::Commit(commit) => self.sender.send(T::from((self.repo_idx, (*commit).clone())))
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Output writer has stopped")),
+            _ => Err(Error::new(ErrorKind::Unsupported, "Workers only write commit records")),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn is_stdout(&self) -> bool {
+        false
+    }
+}
+
+enum JsonlSink {
+    Gz(GzEncoder<BufWriter<Box<dyn Write>>>),
+    Plain(BufWriter<Box<dyn Write>>),
+}
+
+/// Writes one JSON object per line, optionally gzipped.
+struct JsonlWriter {
+    sink: JsonlSink,
+    to_stdout: bool,
+}
+
+impl OutputWriter for JsonlWriter {
+    fn write_record(&mut self, record: &Record) -> Result<(), Error> {
+        let line = serde_json::to_string(record).unwrap_or_default();
+        match &mut self.sink {
+            JsonlSink::Gz(writer) => writeln!(writer, "{}", line),
+            JsonlSink::Plain(writer) => writeln!(writer, "{}", line),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        match &mut self.sink {
+            JsonlSink::Gz(writer) => {
+                writer.try_finish()?;
+                writer.get_mut().flush()
+            },
+            JsonlSink::Plain(writer) => writer.flush(),
+        }
+    }
+
+    fn is_stdout(&self) -> bool {
+        self.to_stdout
+    }
+}
+
+#[derive(Serialize)]
+struct CsvCommitRow<'a> {
+    commit_id: Option<&'a str>,
+    repo_name: Option<&'a str>,
+    author_name: Option<&'a str>,
+    author_email: Option<&'a str>,
+    co_authors: String,
+    ts_secs: i64,
+    ts_offset_mins: i64,
+    committer_name: Option<&'a str>,
+    committer_email: Option<&'a str>,
+    committer_ts_secs: i64,
+    committer_ts_offset_mins: i64,
+    cherry_picked_from: &'a str,
+    parents: String,
+    refs: String,
+    insertions: usize,
+    deletions: usize,
+    files_changed: usize,
+    excluded_insertions: usize,
+    excluded_deletions: usize,
+    excluded_files: usize,
+}
+
+/// Writes one row per commit. Alias and runtime records have no CSV
+/// representation and are skipped.
+struct CsvWriter {
+    writer: csv::Writer<BufWriter<Box<dyn Write>>>,
+    to_stdout: bool,
+}
+
+impl OutputWriter for CsvWriter {
+    fn write_record(&mut self, record: &Record) -> Result<(), Error> {
+        if let Record::Commit(commit) = record {
+            let row = CsvCommitRow {
+                commit_id: commit.commit_id.as_deref(),
+                repo_name: commit.repo_name.as_deref(),
+                author_name: commit.author_name.as_deref(),
+                author_email: commit.author_email.as_deref(),
+                co_authors: commit.co_authors.iter().filter_map(|co_author| co_author.email.as_deref())
+                    .collect::<Vec<&str>>().join(";"),
+                ts_secs: commit.ts_secs,
+                ts_offset_mins: commit.ts_offset_mins,
+                committer_name: commit.committer_name.as_deref(),
+                committer_email: commit.committer_email.as_deref(),
+                committer_ts_secs: commit.committer_ts_secs,
+                committer_ts_offset_mins: commit.committer_ts_offset_mins,
+                cherry_picked_from: commit.cherry_picked_from.as_deref().unwrap_or_default(),
+                parents: commit.parents.join(";"),
+                refs: commit.refs.join(";"),
+                insertions: commit.diff_info.insertions,
+                deletions: commit.diff_info.deletions,
+                files_changed: commit.diff_info.files_changed,
+                excluded_insertions: commit.diff_info.excluded_insertions,
+                excluded_deletions: commit.diff_info.excluded_deletions,
+                excluded_files: commit.diff_info.excluded_files,
+            };
+            self.writer.serialize(row).map_err(Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+
+    fn is_stdout(&self) -> bool {
+        self.to_stdout
+    }
+}
+
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE commits (
+        id INTEGER PRIMARY KEY,
+        commit_id TEXT,
+        repo_name TEXT,
+        author_name TEXT,
+        author_email TEXT,
+        ts_secs INTEGER NOT NULL,
+        ts_offset_mins INTEGER NOT NULL,
+        committer_name TEXT,
+        committer_email TEXT,
+        committer_ts_secs INTEGER NOT NULL,
+        committer_ts_offset_mins INTEGER NOT NULL,
+        cherry_picked_from TEXT,
+        insertions INTEGER NOT NULL,
+        deletions INTEGER NOT NULL,
+        files_changed INTEGER NOT NULL,
+        excluded_insertions INTEGER NOT NULL,
+        excluded_deletions INTEGER NOT NULL,
+        excluded_files INTEGER NOT NULL
+    );
+    CREATE TABLE parents (
+        commit_rowid INTEGER NOT NULL REFERENCES commits(id),
+        position INTEGER NOT NULL,
+        parent_id TEXT NOT NULL
+    );
+    CREATE TABLE co_authors (
+        commit_rowid INTEGER NOT NULL REFERENCES commits(id),
+        name TEXT,
+        email TEXT
+    );
+    CREATE TABLE refs (
+        commit_rowid INTEGER NOT NULL REFERENCES commits(id),
+        ref_name TEXT NOT NULL
+    );
+    CREATE TABLE files (
+        commit_rowid INTEGER NOT NULL REFERENCES commits(id),
+        path_hash TEXT,
+        filename TEXT,
+        v_language TEXT NOT NULL,
+        category TEXT NOT NULL,
+        status TEXT NOT NULL,
+        old_path_hash TEXT,
+        insertions INTEGER NOT NULL,
+        deletions INTEGER NOT NULL
+    );
+    CREATE TABLE aliases (
+        alias TEXT NOT NULL
+    );
+    CREATE TABLE runtime (
+        version TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        errors TEXT NOT NULL,
+        logs TEXT NOT NULL
+    );
+    CREATE TABLE header (
+        schema_version INTEGER NOT NULL,
+        generator TEXT NOT NULL
+    );
+";
+
+/// Writes commits into normalized tables, all inside one transaction
+/// committed on finish.
+struct SqliteWriter {
+    conn: Connection,
+}
+
+impl SqliteWriter {
+    fn new(path: &Path, no_clobber: bool) -> Result<Self, Error> {
+        // truncates or exclusively creates the file, so previous runs are never merged
+        create_file(path, no_clobber)?;
+        let conn = Connection::open(path).map_err(Error::other)?;
+        conn.execute_batch(SQLITE_SCHEMA).map_err(Error::other)?;
+        conn.execute_batch("BEGIN").map_err(Error::other)?;
+        Ok(Self { conn })
+    }
+
+    fn insert_commit(&self, commit: &CommitInfo) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO commits (commit_id, repo_name, author_name, author_email, ts_secs,
+                ts_offset_mins, committer_name, committer_email, committer_ts_secs,
+                committer_ts_offset_mins, cherry_picked_from, insertions, deletions, files_changed,
+                excluded_insertions, excluded_deletions, excluded_files)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+            params![commit.commit_id, commit.repo_name, commit.author_name, commit.author_email,
+                commit.ts_secs, commit.ts_offset_mins, commit.committer_name, commit.committer_email,
+                commit.committer_ts_secs, commit.committer_ts_offset_mins,
+                commit.cherry_picked_from, commit.diff_info.insertions,
+                commit.diff_info.deletions, commit.diff_info.files_changed,
+                commit.diff_info.excluded_insertions, commit.diff_info.excluded_deletions,
+                commit.diff_info.excluded_files])?;
+        let rowid = self.conn.last_insert_rowid();
+        for (position, parent) in commit.parents.iter().enumerate() {
+            self.conn.execute(
+                "INSERT INTO parents (commit_rowid, position, parent_id) VALUES (?1, ?2, ?3)",
+                params![rowid, position, parent])?;
+        }
+        for co_author in &commit.co_authors {
+            self.conn.execute(
+                "INSERT INTO co_authors (commit_rowid, name, email) VALUES (?1, ?2, ?3)",
+                params![rowid, co_author.name, co_author.email])?;
+        }
+        for ref_name in &commit.refs {
+            self.conn.execute(
+                "INSERT INTO refs (commit_rowid, ref_name) VALUES (?1, ?2)",
+                params![rowid, ref_name])?;
+        }
+        for file in &commit.diff_info.file_info {
+            self.conn.execute(
+                "INSERT INTO files (commit_rowid, path_hash, filename, v_language, category, status,
+                    old_path_hash, insertions, deletions) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![rowid, file.path_hash, file.filename, file.v_language, file.category, file.status,
+                    file.old_path_hash, file.insertions, file.deletions])?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputWriter for SqliteWriter {
+    fn write_record(&mut self, record: &Record) -> Result<(), Error> {
+        let res = match record {
+            Record::Header(header) => self.conn.execute(
+                "INSERT INTO header (schema_version, generator) VALUES (?1, ?2)",
+                params![header.schema_version, header.generator])
+                .map(|_| ()),
+            Record::Commit(commit) => self.insert_commit(commit),
+            Record::Alias(aliases) => aliases.alias.iter().try_for_each(|alias| {
+                self.conn.execute("INSERT INTO aliases (alias) VALUES (?1)", params![alias])
+                    .map(|_| ())
+            }),
+            Record::Runtime(rinfo) => self.conn.execute(
+                "INSERT INTO runtime (version, timestamp, errors, logs) VALUES (?1, ?2, ?3, ?4)",
+                params![rinfo.version, rinfo.timestamp,
+                    serde_json::to_string(&rinfo.errors).unwrap_or_default(),
+                    serde_json::to_string(&rinfo.logs).unwrap_or_default()])
+                .map(|_| ()),
+        };
+        res.map_err(Error::other)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if self.conn.is_autocommit() {
+            return Ok(());
+        }
+        self.conn.execute_batch("COMMIT").map_err(Error::other)
+    }
+
+    fn is_stdout(&self) -> bool {
+        false
+    }
+}
+
+pub struct IoErrWriter {
+    path: PathBuf,
+    no_clobber: bool,
+    iowriter: Option<BufWriter<File>>
+}
+
+impl IoErrWriter {
+    /// Placed next to the archive, with the same `output.timestamp` and
+    /// `output.no_clobber` treatment.
+    pub fn from_config(config: &Config) -> Self {
+        let mut path = output_dir(config).join("io_errors.txt");
+        if config.get_bool("output.timestamp") {
+            add_timestamp(&mut path);
+        }
+        Self{ path, no_clobber: config.get_bool("output.no_clobber"), iowriter: None }
+    }
 
-    pub fn writeln(&mut self, line: &str) -> Result<(), Error>{
-        writeln!(self.writer, "{}", line.to_string())
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     pub fn write_io_err(&mut self, line: &str) -> Result<(), Error>{
@@ -31,22 +580,36 @@ impl OutputWriter {
                 Ok(io_writer) => {self.iowriter = io_writer;},
                 Err(error) => { return Err(error); },
             }
-            
+
         }
         let writer_borrow = self.iowriter.as_mut().expect("Checked, is some");
-        writeln!(writer_borrow, "{}", line.to_string())
+        writeln!(writer_borrow, "{}", line)
     }
 
     pub fn finish(&mut self) -> Result<(), Error>{
-        if self.iowriter.is_some() {
-            let writer_borrow = self.iowriter.as_mut().expect("Checked, is some");
+        if let Some(writer_borrow) = self.iowriter.as_mut() {
             writer_borrow.flush()?;
         }
-        self.writer.try_finish()
+        Ok(())
     }
 
     fn create_io_file_writer(&self) -> Result<Option<BufWriter<File>>, Error>{
-        let iofile = File::create("io_errors.txt")?;
+        let iofile = create_file(&self.path, self.no_clobber)?;
         Ok(Some(BufWriter::new(iofile)))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// schema/record.schema.json has to be regenerated with `devprofiler schema`
+    /// whenever a record struct changes.
+    #[test]
+    fn checked_in_schema_is_current() {
+        let checked_in: serde_json::Value = serde_json::from_str(include_str!("../schema/record.schema.json"))
+            .expect("Checked-in schema is valid JSON");
+        let generated = serde_json::to_value(record_schema()).expect("Schema serializes to JSON");
+        assert_eq!(generated, checked_in, "schema/record.schema.json is stale, regenerate it with devprofiler schema");
+    }
+}